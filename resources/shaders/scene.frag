@@ -14,6 +14,7 @@ in vec3 v_position;
 in vec4 v_color;
 in vec3 v_normal;
 in vec2 v_uv;
+flat in uint v_biome;
 in vec3 v_model_position;
 
 uniform float u_time;
@@ -28,6 +29,8 @@ uniform uint u_current_planet_id;   // Just in case multiple planets should be r
 uniform bool u_has_texture;
 
 #define N_LAYERS 5
+#define N_BIOMES 13
+#define BIOME_NONE 0
 
 // Array of planets
 #define MAX_PLANETS 32
@@ -46,6 +49,7 @@ uniform struct Planet {
     vec3 color_scheme[N_LAYERS];        // Colours of height map
     float color_thresholds[N_LAYERS-1]; // Levels for changing colour
     float color_blending;               // Level of blending between colours
+    vec3 biome_colors[N_BIOMES];        // Colours of biomes, by biome id
     vec3 ocean_dark_color;  // Colour of the ocean
     vec3 ocean_light_color; // Colour of the ocean
 } u_planets[MAX_PLANETS];
@@ -158,9 +162,13 @@ vec4 planet_shader(vec3 position, vec3 normal, uint planet_id)
     float h = (length(position) - 0.5) * 2.0;
     vec3 diffuse_color;
     //-------------------------------------------------------------------------/
-    // Simple height map sets diffuse colour
+    // Biome baked into the vertex sets diffuse colour, otherwise a simple
+    // height map
     //-------------------------------------------------------------------------/
-    if (h < u_planets[planet_id].color_thresholds[0]) {
+    if (v_biome != BIOME_NONE && v_biome < N_BIOMES) {
+        diffuse_color = u_planets[planet_id].biome_colors[v_biome];
+    }
+    else if (h < u_planets[planet_id].color_thresholds[0]) {
         diffuse_color = u_planets[planet_id].color_scheme[0];
         //diffuse_color = vec3(0.9137, 0.5176, 0.0);
     }
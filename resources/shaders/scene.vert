@@ -1,28 +1,62 @@
 #version 460 core
 
-in vec3 position;
-in vec4 color;
-in vec3 normal;
-in vec2 uv;
+// Locations as set up by `Mesh::interleave`
+layout (location = 0) in vec3 position;
+layout (location = 1) in vec4 color;
+layout (location = 2) in vec3 normal;
+layout (location = 3) in vec2 uv;
+layout (location = 4) in uint biome;
+layout (location = 5) in float parent_height; // Parent level's surface above the vertex, for terrain patches
+layout (location = 6) in vec4 tangent;        // Along the first texture coordinate, bitangent handedness in w
 
 out vec3 v_position;
 out vec4 v_color;
 out vec3 v_normal;
 out vec2 v_uv;
+flat out uint v_biome;
 out vec3 v_model_position;
+out vec4 v_tangent;
 
 uniform uint u_node_type;
 uniform mat4 u_model;       // Transforms model into world coordinates
 uniform mat4 u_mvp;         // Model-view-perspective matrix
+uniform vec3 u_model_offset; // Vertex positions relative to the model, for terrain patches
+uniform vec3 u_player_position;
+uniform float u_morph_error;     // Parent patch's error in world units, 0 to not morph
+uniform float u_lod_pixel_scale; // Pixels covered by one unit at unit distance
+uniform float u_lod_pixel_error; // Error in pixels where patches are subdivided
+uniform float u_lod_morph_range; // Times the pixel error where morphing ends
+
+// How much of the parent's shape a terrain patch vertex takes, from the same
+// metric that subdivides the patches: all of it where the parent's error
+// covers the pixel error, none where it covers u_lod_morph_range times that
+float morph_factor(vec3 vertex)
+{
+    if (u_morph_error <= 0.0) {
+        return 0.0;
+    }
+    float distance = length((u_model * vec4(vertex, 1.0)).xyz - u_player_position);
+    float pixels = u_morph_error / max(distance, 1e-6) * u_lod_pixel_scale;
+    float range = u_lod_pixel_error * (u_lod_morph_range - 1.0);
+    return clamp((u_lod_pixel_error * u_lod_morph_range - pixels) / range, 0.0, 1.0);
+}
 
 void main()
 {
-    v_position = position;
+    // Geomorph between detail levels, along the direction from the centre
+    vec3 morphed = position;
+    float morph = morph_factor(position);
+    if (morph > 0.0) {
+        morphed += normalize(position + u_model_offset) * parent_height * morph;
+    }
+    v_position = morphed + u_model_offset;
     v_normal = normal;
-    v_model_position = position;
+    v_tangent = tangent;
+    v_model_position = v_position;
     v_color = color;
     v_uv = uv;
-    vec4 pos = u_mvp * vec4(v_position, 1.0f);
+    v_biome = biome;
+    vec4 pos = u_mvp * vec4(morphed, 1.0f);
     gl_Position = (u_node_type == 1) ? pos.xyww : pos;
 
 }
\ No newline at end of file
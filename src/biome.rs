@@ -0,0 +1,161 @@
+use nalgebra_glm as glm;
+use std::collections::VecDeque;
+
+use crate::cubesphere::CubeMap;
+use crate::globals::N_BIOMES;
+
+//-----------------------------------------------------------------------------/
+// Biomes
+//-----------------------------------------------------------------------------/
+// Terrain is classified by temperature and moisture, roughly after Whittaker's
+// biome diagram. Temperature falls towards the poles and with altitude,
+// moisture comes from a noise field and the distance to the ocean. Both are in
+// [0, 1] before the classification, but may fall outside it.
+
+/// Biome of a vertex, passed to the shader as its index. `None` leaves the
+/// colour to the height based colour scheme.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[repr(u32)]
+pub enum Biome {
+    #[default]
+    None = 0,
+    Seabed,
+    Beach,
+    IceCap,
+    Tundra,
+    Taiga,
+    Grassland,
+    TemperateForest,
+    Desert,
+    Savanna,
+    Rainforest,
+    Lake,
+    River,
+}
+
+/// Parameters for biome classification and biome colours
+#[derive(Debug, Copy, Clone)]
+pub struct BiomeParams {
+    pub enabled: bool,
+    pub temperature: f32,        // At the equator at ocean level
+    pub polar_cooling: f32,      // Drop in temperature from equator to pole
+    pub lapse_rate: f32,         // Drop in temperature at max height
+    pub moisture: f32,           // Mean of the moisture noise
+    pub moisture_frequency: f32, // Size of the moisture noise
+    pub ocean_moisture: f32,     // Weight of closeness to ocean in moisture
+    pub coast_distance: f32,     // Falloff of ocean moisture, in radians
+    pub ice_temperature: f32,    // Ice cap below this temperature
+    pub beach_height: f32,       // Beach below this fraction of max height
+    pub resolution: usize,       // Texels per face side for ocean distance
+    pub colors: [glm::Vec3; N_BIOMES],
+}
+
+impl Default for BiomeParams {
+    fn default() -> Self {
+        BiomeParams {
+            enabled: false,
+            temperature: 1.0,
+            polar_cooling: 1.1,
+            lapse_rate: 0.6,
+            moisture: 0.5,
+            moisture_frequency: 2.0,
+            ocean_moisture: 0.4,
+            coast_distance: 0.15,
+            ice_temperature: 0.05,
+            beach_height: 0.02,
+            resolution: 64,
+            colors: [
+                glm::vec3(0.0, 0.0, 0.0),    // None
+                glm::vec3(0.35, 0.33, 0.25), // Seabed
+                glm::vec3(0.86, 0.8, 0.58),  // Beach
+                glm::vec3(0.94, 0.96, 1.0),  // IceCap
+                glm::vec3(0.55, 0.56, 0.47), // Tundra
+                glm::vec3(0.16, 0.3, 0.22),  // Taiga
+                glm::vec3(0.5, 0.62, 0.27),  // Grassland
+                glm::vec3(0.2, 0.42, 0.15),  // TemperateForest
+                glm::vec3(0.87, 0.71, 0.45), // Desert
+                glm::vec3(0.68, 0.63, 0.31), // Savanna
+                glm::vec3(0.07, 0.33, 0.09), // Rainforest
+                glm::vec3(0.05, 0.25, 0.35), // Lake
+                glm::vec3(0.08, 0.32, 0.4),  // River
+            ],
+        }
+    }
+}
+
+/// Biome from temperature and moisture, and height relative to max height
+pub fn classify(
+    params: &BiomeParams,
+    temperature: f32,
+    moisture: f32,
+    height: f32,
+    has_ocean: bool,
+) -> Biome {
+    use Biome::*;
+    if has_ocean && height < 0.0 {
+        return Seabed;
+    }
+    if temperature < params.ice_temperature {
+        return IceCap;
+    }
+    if has_ocean && height < params.beach_height {
+        return Beach;
+    }
+    if temperature < 0.3 {
+        if moisture < 0.45 {
+            Tundra
+        } else {
+            Taiga
+        }
+    } else if temperature < 0.65 {
+        if moisture < 0.3 {
+            Desert
+        } else if moisture < 0.55 {
+            Grassland
+        } else {
+            TemperateForest
+        }
+    } else if moisture < 0.35 {
+        Desert
+    } else if moisture < 0.6 {
+        Savanna
+    } else {
+        Rainforest
+    }
+}
+
+/// Angular distance from each texel to the closest texel below ocean level,
+/// counted in steps between neighbouring texels. At most pi, the far side of
+/// the sphere, which is also the distance without ocean. Kept finite, as
+/// sampling weighs texels by 0 as well.
+pub fn ocean_distance(heights: &CubeMap) -> CubeMap {
+    let mut distance = CubeMap::new(heights.res);
+    distance
+        .data
+        .iter_mut()
+        .for_each(|d| *d = std::f32::consts::PI);
+    let step = heights.texel_angle();
+
+    // Breadth first search from all ocean texels at once
+    let mut queue = VecDeque::new();
+    for idx in 0..heights.data.len() {
+        if heights.data[idx] < 0.0 && heights.canonical(idx) == idx {
+            distance.data[idx] = 0.0;
+            queue.push_back(idx);
+        }
+    }
+    while let Some(idx) = queue.pop_front() {
+        let d = distance.data[idx] + step;
+        for n in heights.neighbours(idx) {
+            if d < distance.data[n] {
+                distance.data[n] = d;
+                queue.push_back(n);
+            }
+        }
+    }
+    // Only canonical texels were visited, copy to the rest
+    for idx in 0..distance.data.len() {
+        distance.data[idx] = distance.data[heights.canonical(idx)];
+    }
+    distance
+}
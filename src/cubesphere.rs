@@ -0,0 +1,532 @@
+use nalgebra_glm as glm;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+//-----------------------------------------------------------------------------/
+// Cube faces
+//-----------------------------------------------------------------------------/
+// A face is the plane y = 1, x and z in [-1, 1], rotated into place. Face
+// coordinates (a, b) run along the rotated x and z axes, like `Mesh::cs_plane`.
+
+/// Rotation of each cube face, applied around x, y and z in that order
+pub fn face_rotations() -> [glm::TVec3<f32>; 6] {
+    [
+        glm::vec3(0.0, 0.0, 0.0),                          // Top
+        glm::vec3(std::f32::consts::PI, 0.0, 0.0),         // Bottom
+        glm::vec3(std::f32::consts::FRAC_PI_2, 0.0, 0.0),  // Front
+        glm::vec3(-std::f32::consts::FRAC_PI_2, 0.0, 0.0), // Back
+        glm::vec3(0.0, 0.0, -std::f32::consts::FRAC_PI_2), // Left
+        glm::vec3(0.0, 0.0, std::f32::consts::FRAC_PI_2),  // Right
+    ]
+}
+
+/// Rotate a vector the same way `Mesh::cs_plane` rotates its vertices
+pub fn rotate(v: &glm::Vec3, rotation: &glm::Vec3) -> glm::Vec3 {
+    let v = glm::rotate_x_vec3(v, rotation.x);
+    let v = glm::rotate_y_vec3(&v, rotation.y);
+    glm::rotate_z_vec3(&v, rotation.z)
+}
+
+/// How points on the face planes are moved onto the sphere. Normalising
+/// bunches points up towards the face edges, the others spread them out.
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub enum Projection {
+    Normalized, // Straight out from the centre, cells up to 5 times larger in the middle
+    Spherified, // Cells within 1.3 times in area, but up to 1.7 times as long
+    #[default]
+    Tangent, // Equal angles along face coordinates, cells within 1.4 times
+}
+
+impl Projection {
+    pub const ALL: [Projection; 3] = [
+        Projection::Normalized,
+        Projection::Spherified,
+        Projection::Tangent,
+    ];
+}
+
+static PROJECTION: AtomicU8 = AtomicU8::new(Projection::Tangent as u8);
+static PROJECTION_FIXED: AtomicBool = AtomicBool::new(false);
+
+/// Projection used by `cs_point` and `cs_inverse`. Set it before generating
+/// terrain, patches of different projections don't line up. Panics when
+/// changed after `fix_projection`.
+pub fn set_projection(projection: Projection) {
+    assert!(
+        !PROJECTION_FIXED.load(Ordering::Relaxed) || self::projection() == projection,
+        "cube projection changed to {:?} after terrain was baked with {:?}",
+        projection,
+        self::projection()
+    );
+    PROJECTION.store(projection as u8, Ordering::Relaxed);
+}
+
+/// Keep the projection as it is, terrain has been baked with it
+pub fn fix_projection() {
+    PROJECTION_FIXED.store(true, Ordering::Relaxed);
+}
+
+pub fn projection() -> Projection {
+    Projection::ALL[PROJECTION.load(Ordering::Relaxed) as usize]
+}
+
+/// Point on the unit sphere for a point on the face plane y = 1, rotated into
+/// place, by the selected `Projection`
+pub fn cs_point(p: &glm::DVec3, rotation: &glm::Vec3) -> glm::DVec3 {
+    cs_point_with(p, rotation, projection())
+}
+
+/// `cs_point` by the given projection
+pub fn cs_point_with(p: &glm::DVec3, rotation: &glm::Vec3, projection: Projection) -> glm::DVec3 {
+    let s = match projection {
+        Projection::Normalized => glm::normalize(p),
+        // https://mathproofs.blogspot.com/2005/07/mapping-cube-to-sphere.html
+        Projection::Spherified => {
+            let (x2, y2, z2) = (p.x * p.x, p.y * p.y, p.z * p.z);
+            glm::vec3(
+                p.x * (1.0 - y2 / 2.0 - z2 / 2.0 + y2 * z2 / 3.0).sqrt(),
+                p.y * (1.0 - x2 / 2.0 - z2 / 2.0 + x2 * z2 / 3.0).sqrt(),
+                p.z * (1.0 - x2 / 2.0 - y2 / 2.0 + x2 * y2 / 3.0).sqrt(),
+            )
+        }
+        // Angle from the face centre along each axis grows evenly
+        Projection::Tangent => {
+            let quarter = std::f64::consts::FRAC_PI_4;
+            glm::normalize(&glm::vec3(
+                (p.x * quarter).tan(),
+                p.y,
+                (p.z * quarter).tan(),
+            ))
+        }
+    };
+    let s = glm::rotate_x_vec3(&s, rotation.x as f64);
+    let s = glm::rotate_y_vec3(&s, rotation.y as f64);
+    glm::rotate_z_vec3(&s, rotation.z as f64)
+}
+
+/// Face and face plane coordinates (x, z) of a direction, inverse of
+/// `cs_point`. The face indexes `face_rotations`.
+pub fn cs_inverse(dir: &glm::DVec3) -> (usize, f64, f64) {
+    cs_inverse_with(dir, projection())
+}
+
+/// `cs_inverse` by the given projection
+pub fn cs_inverse_with(dir: &glm::DVec3, projection: Projection) -> (usize, f64, f64) {
+    let rotations = face_rotations();
+    let frames = face_frames();
+    let face = (0..6)
+        .max_by(|&i, &j| {
+            let di = glm::dot(dir, &frames[i].normal.cast());
+            let dj = glm::dot(dir, &frames[j].normal.cast());
+            di.total_cmp(&dj)
+        })
+        .unwrap();
+    // Undo the face rotation, z, y and x in that order
+    let r = rotations[face].cast::<f64>();
+    let d = glm::rotate_z_vec3(&glm::normalize(dir), -r.z);
+    let d = glm::rotate_y_vec3(&d, -r.y);
+    let d = glm::rotate_x_vec3(&d, -r.x);
+
+    // Gnomonic projection, the inverse of normalising
+    let (mut x, mut z) = (d.x / d.y, d.z / d.y);
+    match projection {
+        Projection::Normalized => (),
+        Projection::Tangent => {
+            let quarter = std::f64::consts::FRAC_PI_4;
+            (x, z) = (x.atan() / quarter, z.atan() / quarter);
+        }
+        Projection::Spherified => (x, z) = spherified_inverse(&d, x, z),
+    }
+    (face, x.clamp(-1.0, 1.0), z.clamp(-1.0, 1.0))
+}
+
+/// Face plane coordinates of a direction around the face's y axis, for the
+/// spherified cube, starting from the gnomonic projection (x, z)
+fn spherified_inverse(d: &glm::DVec3, mut x: f64, mut z: f64) -> (f64, f64) {
+    // On the plane y = 1 the mapping is x' = x sqrt(1/2 - z^2/6) and
+    // z' = z sqrt(1/2 - x^2/6). Newton's method.
+    for _ in 0..8 {
+        let (sx, sz) = ((0.5 - z * z / 6.0).sqrt(), (0.5 - x * x / 6.0).sqrt());
+        let (fx, fz) = (x * sx - d.x, z * sz - d.z);
+        // Jacobian of (x', z')
+        let (j11, j12) = (sx, -x * z / (6.0 * sx));
+        let (j21, j22) = (-x * z / (6.0 * sz), sz);
+        let det = j11 * j22 - j12 * j21;
+        x -= (j22 * fx - j12 * fz) / det;
+        z -= (j11 * fz - j21 * fx) / det;
+    }
+    (x, z)
+}
+
+/// Directions on the unit sphere of the vertices of a patch, in the same
+/// order as `Mesh::cs_plane`
+pub fn patch_directions(
+    scale: &glm::Vec3,
+    rotation: &glm::Vec3,
+    position: &glm::Vec3,
+    subdivisions: usize,
+) -> Vec<glm::DVec3> {
+    let (scale, position) = (scale.cast::<f64>(), position.cast::<f64>());
+    let step = scale / subdivisions as f64 * 2.0;
+    let mut directions = Vec::with_capacity((subdivisions + 1) * (subdivisions + 1));
+    for z in 0..=subdivisions {
+        for x in 0..=subdivisions {
+            let p = glm::vec3(
+                position.x - scale.x + step.x * x as f64,
+                1.0,
+                position.z - scale.z + step.z * z as f64,
+            );
+            directions.push(glm::normalize(&cs_point(&p, rotation)));
+        }
+    }
+    directions
+}
+
+/// Orientation of a cube face. The point at face coordinates (a, b) is
+/// `normal + a * u + b * v`.
+#[derive(Debug, Copy, Clone)]
+pub struct FaceFrame {
+    pub normal: glm::Vec3,
+    pub u: glm::Vec3,
+    pub v: glm::Vec3,
+}
+
+pub fn face_frames() -> [FaceFrame; 6] {
+    face_rotations().map(|r| FaceFrame {
+        // Rounded to get exact axes rather than sin(pi) rounding errors
+        normal: rotate(&glm::vec3(0.0, 1.0, 0.0), &r).map(|x| x.round()),
+        u: rotate(&glm::vec3(1.0, 0.0, 0.0), &r).map(|x| x.round()),
+        v: rotate(&glm::vec3(0.0, 0.0, 1.0), &r).map(|x| x.round()),
+    })
+}
+
+/// Direction on the unit sphere from face coordinates
+pub fn face_to_dir(frames: &[FaceFrame; 6], face: usize, a: f32, b: f32) -> glm::Vec3 {
+    let f = &frames[face];
+    glm::normalize(&(f.normal + f.u * a + f.v * b))
+}
+
+/// Face and face coordinates of a direction, inverse of `face_to_dir`
+pub fn dir_to_face(frames: &[FaceFrame; 6], dir: &glm::Vec3) -> (usize, f32, f32) {
+    let mut face = 0;
+    let mut max = f32::MIN;
+    for (i, f) in frames.iter().enumerate() {
+        let d = glm::dot(dir, &f.normal);
+        if d > max {
+            max = d;
+            face = i;
+        }
+    }
+    let f = &frames[face];
+    (face, glm::dot(dir, &f.u) / max, glm::dot(dir, &f.v) / max)
+}
+
+/// Two unit vectors orthogonal to each other and to `dir`
+pub fn tangent_basis(dir: &glm::Vec3) -> (glm::Vec3, glm::Vec3) {
+    let helper = if dir.y.abs() < 0.9 {
+        glm::vec3(0.0, 1.0, 0.0)
+    } else {
+        glm::vec3(1.0, 0.0, 0.0)
+    };
+    let t1 = glm::normalize(&glm::cross(&helper, dir));
+    let t2 = glm::cross(dir, &t1);
+    (t1, t2)
+}
+
+//-----------------------------------------------------------------------------/
+// CubeMap
+//-----------------------------------------------------------------------------/
+
+/// Grid of values covering the sphere, `res + 1` texels along each side of
+/// each face. Texels on the face edges exist on two or three faces, these are
+/// kept equal so sampling is continuous across edges.
+#[derive(Debug, Clone)]
+pub struct CubeMap {
+    pub res: usize,
+    pub data: Vec<f32>,
+    frames: [FaceFrame; 6],
+    shared: HashMap<usize, Vec<usize>>, // Edge texels on other faces
+}
+
+impl CubeMap {
+    pub fn new(res: usize) -> Self {
+        let mut map = CubeMap {
+            res,
+            data: vec![0.0; 6 * (res + 1) * (res + 1)],
+            frames: face_frames(),
+            shared: HashMap::new(),
+        };
+        // Link edge texels with their copies on neighbouring faces
+        for face in 0..6 {
+            for j in 0..=res {
+                for i in 0..=res {
+                    if i != 0 && j != 0 && i != res && j != res {
+                        continue;
+                    }
+                    let dir = map.texel_dir(face, i, j);
+                    let idx = map.index(face, i, j);
+                    for other in (0..6).filter(|&f| f != face) {
+                        let n = glm::dot(&dir, &map.frames[other].normal);
+                        if n <= 0.0 {
+                            continue;
+                        }
+                        let a = glm::dot(&dir, &map.frames[other].u) / n;
+                        let b = glm::dot(&dir, &map.frames[other].v) / n;
+                        if a.abs() > 1.0 + 1e-4 || b.abs() > 1.0 + 1e-4 {
+                            continue;
+                        }
+                        let (oi, oj) = (map.to_texel(a).round(), map.to_texel(b).round());
+                        let other_idx = map.index(other, oi as usize, oj as usize);
+                        map.shared.entry(idx).or_default().push(other_idx);
+                    }
+                }
+            }
+        }
+        map
+    }
+
+    /// Fill a map with values of a function of the direction. Each face is
+    /// computed on its own thread.
+    pub fn from_fn<F: Fn(&glm::Vec3) -> f32 + Sync>(res: usize, f: F) -> Self {
+        let mut map = Self::new(res);
+        let side = (res + 1) * (res + 1);
+        let frames = map.frames;
+        std::thread::scope(|s| {
+            for (face, chunk) in map.data.chunks_mut(side).enumerate() {
+                let f = &f;
+                s.spawn(move || {
+                    for (k, value) in chunk.iter_mut().enumerate() {
+                        let (i, j) = (k % (res + 1), k / (res + 1));
+                        let a = -1.0 + 2.0 * i as f32 / res as f32;
+                        let b = -1.0 + 2.0 * j as f32 / res as f32;
+                        *value = f(&face_to_dir(&frames, face, a, b));
+                    }
+                });
+            }
+        });
+        // Faces may round shared directions differently
+        for (&idx, others) in &map.shared {
+            let first = others.iter().fold(idx, |a, &b| a.min(b));
+            map.data[idx] = map.data[first];
+        }
+        map
+    }
+
+    pub fn index(&self, face: usize, i: usize, j: usize) -> usize {
+        (face * (self.res + 1) + j) * (self.res + 1) + i
+    }
+
+    /// Face coordinate in [-1, 1] to texel coordinate in [0, res]
+    fn to_texel(&self, a: f32) -> f32 {
+        ((a + 1.0) / 2.0 * self.res as f32).clamp(0.0, self.res as f32)
+    }
+
+    pub fn texel_dir(&self, face: usize, i: usize, j: usize) -> glm::Vec3 {
+        let a = -1.0 + 2.0 * i as f32 / self.res as f32;
+        let b = -1.0 + 2.0 * j as f32 / self.res as f32;
+        face_to_dir(&self.frames, face, a, b)
+    }
+
+    /// Angle between neighbouring texels at the centre of a face
+    pub fn texel_angle(&self) -> f32 {
+        2.0 / self.res as f32
+    }
+
+    /// Texel indices and bilinear weights for the four texels around `dir`
+    fn footprint(&self, dir: &glm::Vec3) -> [(usize, f32); 4] {
+        let (face, a, b) = dir_to_face(&self.frames, dir);
+        let (x, y) = (self.to_texel(a), self.to_texel(b));
+        let (i, j) = (
+            (x.floor() as usize).min(self.res - 1),
+            (y.floor() as usize).min(self.res - 1),
+        );
+        let (fx, fy) = (x - i as f32, y - j as f32);
+        [
+            (self.index(face, i, j), (1.0 - fx) * (1.0 - fy)),
+            (self.index(face, i + 1, j), fx * (1.0 - fy)),
+            (self.index(face, i, j + 1), (1.0 - fx) * fy),
+            (self.index(face, i + 1, j + 1), fx * fy),
+        ]
+    }
+
+    /// Bilinear sample at a direction
+    pub fn sample(&self, dir: &glm::Vec3) -> f32 {
+        self.footprint(dir)
+            .iter()
+            .map(|&(idx, w)| self.data[idx] * w)
+            .sum()
+    }
+
+    /// Add to the texels around a direction, distributed by bilinear weights
+    pub fn splat(&mut self, dir: &glm::Vec3, amount: f32) {
+        for (idx, w) in self.footprint(dir) {
+            self.add(idx, amount * w);
+        }
+    }
+
+    /// The lowest index among a texel and its copies on neighbouring faces
+    pub fn canonical(&self, idx: usize) -> usize {
+        match self.shared.get(&idx) {
+            Some(shared) => shared.iter().fold(idx, |a, &b| a.min(b)),
+            None => idx,
+        }
+    }
+
+    /// Canonical indices of the four texels next to a texel, continuing onto
+    /// the neighbouring face at the edges
+    pub fn neighbours(&self, idx: usize) -> [usize; 4] {
+        let side = self.res + 1;
+        let (face, j, i) = (idx / (side * side), idx / side % side, idx % side);
+        let on_face = self.face_neighbours(face, i, j);
+        [(1, 0), (-1, 0), (0, 1), (0, -1)].map(|(di, dj)| {
+            let (ni, nj) = (i as i32 + di, j as i32 + dj);
+            if (0..=self.res as i32).contains(&ni) && (0..=self.res as i32).contains(&nj) {
+                return self.canonical(self.index(face, ni as usize, nj as usize));
+            }
+            // Off the face, the texel next to a copy of this one on the face
+            // across the edge, and not next to it on this face. Corners of
+            // the cube only have three neighbours, both ways off the face
+            // find the third.
+            self.shared[&idx]
+                .iter()
+                .flat_map(|&other| {
+                    let (face, j, i) = (other / (side * side), other / side % side, other % side);
+                    self.face_neighbours(face, i, j)
+                })
+                .find(|n| !on_face.contains(n))
+                .unwrap()
+        })
+    }
+
+    /// Canonical indices of the texels next to a texel on its own face
+    fn face_neighbours(&self, face: usize, i: usize, j: usize) -> Vec<usize> {
+        [(1, 0), (-1, 0), (0, 1), (0, -1)]
+            .iter()
+            .map(|&(di, dj)| (i as i32 + di, j as i32 + dj))
+            .filter(|&(ni, nj)| {
+                (0..=self.res as i32).contains(&ni) && (0..=self.res as i32).contains(&nj)
+            })
+            .map(|(ni, nj)| self.canonical(self.index(face, ni as usize, nj as usize)))
+            .collect()
+    }
+
+    /// Add to a texel and any copies of it on neighbouring faces
+    pub fn add(&mut self, idx: usize, amount: f32) {
+        self.data[idx] += amount;
+        let side = self.res + 1;
+        let (i, j) = (idx % side, idx / side % side);
+        if i != 0 && j != 0 && i != self.res && j != self.res {
+            return;
+        }
+        if let Some(shared) = self.shared.get(&idx) {
+            for &other in shared {
+                self.data[other] += amount;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RES: usize = 8;
+
+    fn is_edge(map: &CubeMap, idx: usize) -> bool {
+        let side = map.res + 1;
+        let (i, j) = (idx % side, idx / side % side);
+        i == 0 || j == 0 || i == map.res || j == map.res
+    }
+
+    #[test]
+    fn inverse_undoes_every_projection() {
+        let steps = 16;
+        for projection in Projection::ALL {
+            for (face, rotation) in face_rotations().iter().enumerate() {
+                for i in 0..=steps {
+                    for j in 0..=steps {
+                        let x = -1.0 + 2.0 * i as f64 / steps as f64;
+                        let z = -1.0 + 2.0 * j as f64 / steps as f64;
+                        let dir = cs_point_with(&glm::vec3(x, 1.0, z), rotation, projection);
+                        let (f, a, b) = cs_inverse_with(&dir, projection);
+                        // Edges belong to either face, they must give the same direction up to
+                        // the single precision face rotations
+                        let back =
+                            cs_point_with(&glm::vec3(a, 1.0, b), &face_rotations()[f], projection);
+                        assert!(
+                            glm::distance(&dir, &back) < 1e-6,
+                            "{:?} of face {} at {}, {}",
+                            projection,
+                            face,
+                            x,
+                            z
+                        );
+                        if i % steps != 0 && j % steps != 0 {
+                            assert_eq!(f, face);
+                            assert!((a - x).abs() < 1e-9 && (b - z).abs() < 1e-9);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edge_texels_are_shared_between_faces() {
+        let map = CubeMap::new(RES);
+        for idx in 0..map.data.len() {
+            let side = map.res + 1;
+            let (face, j, i) = (idx / (side * side), idx / side % side, idx % side);
+            let shared = map.shared.get(&idx).map_or(0, |s| s.len());
+            if !is_edge(&map, idx) {
+                assert_eq!(shared, 0);
+                continue;
+            }
+            // Corners of the cube lie on three faces, other edge texels on two
+            let corner = (i == 0 || i == map.res) && (j == 0 || j == map.res);
+            assert_eq!(shared, if corner { 2 } else { 1 }, "texel {}", idx);
+            let dir = map.texel_dir(face, i, j);
+            for &other in &map.shared[&idx] {
+                let (face, j, i) = (other / (side * side), other / side % side, other % side);
+                assert!(glm::distance(&dir, &map.texel_dir(face, i, j)) < 1e-5);
+                assert!(map.shared[&other].contains(&idx));
+            }
+        }
+
+        // Copies stay equal when filled and when added to
+        let mut map = CubeMap::from_fn(RES, |d| d.x + 2.0 * d.y + 3.0 * d.z);
+        for idx in (0..map.data.len()).step_by(7) {
+            map.add(idx, 1.0);
+        }
+        for (&idx, others) in &map.shared {
+            for &other in others {
+                assert_eq!(map.data[idx], map.data[other]);
+            }
+        }
+    }
+
+    #[test]
+    fn neighbours_are_symmetric_across_faces() {
+        let map = CubeMap::new(RES);
+        for idx in (0..map.data.len()).filter(|&idx| map.canonical(idx) == idx) {
+            let dir = map.texel_dir(
+                idx / (RES + 1).pow(2),
+                idx % (RES + 1),
+                idx / (RES + 1) % (RES + 1),
+            );
+            for n in map.neighbours(idx) {
+                assert_eq!(map.canonical(n), n);
+                assert!(
+                    map.neighbours(n).contains(&idx),
+                    "texel {} is next to {}, but not the other way",
+                    idx,
+                    n
+                );
+                let side = RES + 1;
+                let other = map.texel_dir(n / (side * side), n % side, n / side % side);
+                let angle = glm::dot(&dir, &other).clamp(-1.0, 1.0).acos();
+                assert!(angle > 0.0 && angle < 1.5 * map.texel_angle());
+            }
+        }
+    }
+}
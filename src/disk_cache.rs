@@ -0,0 +1,314 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::mesh::Mesh;
+use crate::patch_address::PatchAddress;
+
+//-----------------------------------------------------------------------------/
+// Disk cache
+//-----------------------------------------------------------------------------/
+// Generated terrain patches are stored on disk, one file per patch, so the
+// same terrain isn't generated again in the next session. A file is named by
+// the planet seed and a hash of the terrain parameters, for its directory,
+// then the cube face and quadtree path. Its header holds the format version
+// and the key again. A file with another version or key is stale, and is
+// overwritten when the patch is generated. Directories of other seeds and
+// parameters are stale as a whole, and are removed by `prune`.
+//
+// Layout, little endian:
+//   magic       8 bytes, "PPATCHES"
+//   version     u32
+//   seed        u32
+//   params      u64, hash of the terrain parameters
+//   face        u8
+//   path length u8, then a quadrant index per level
+//   error       f32
+//   vertices, normals, texture coordinates, colors, parent heights, tangents
+//   as f32 arrays, biomes and indices as u32 arrays, each prefixed by its u32
+//   length
+
+const MAGIC: &[u8; 8] = b"PPATCHES";
+const VERSION: u32 = 5;
+static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// What a cached patch was generated from
+#[derive(Debug, Clone)]
+pub struct PatchKey {
+    pub directory: PathBuf, // Of the whole cache, `patch_cache_dir` in settings.conf
+    pub seed: u32,
+    pub params: u64, // Hash of the terrain parameters
+    pub address: PatchAddress,
+}
+
+impl PatchKey {
+    fn file(&self) -> PathBuf {
+        let address = &self.address;
+        let path: String = address.path.iter().map(|q| char::from(b'0' + q)).collect();
+        self.directory
+            .join(planet_directory(self.seed, self.params))
+            .join(format!("{}-{}.patch", address.face, path))
+    }
+}
+
+/// Name of the directory holding all patches of a seed and parameters
+fn planet_directory(seed: u32, params: u64) -> String {
+    format!("{:08x}-{:016x}", seed, params)
+}
+
+/// Remove the patches of every seed and parameters but those in `keep`,
+/// left by earlier runs. Only directories named like `planet_directory` are
+/// touched. Returns how many were removed.
+pub fn prune(directory: &Path, keep: &[(u32, u64)]) -> io::Result<usize> {
+    let keep: Vec<String> = (keep.iter())
+        .map(|&(seed, params)| planet_directory(seed, params))
+        .collect();
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_planet = name.len() == 25
+            && name.char_indices().all(|(i, c)| match i {
+                8 => c == '-',
+                _ => c.is_ascii_hexdigit(),
+            });
+        if is_planet && entry.file_type()?.is_dir() && !keep.contains(&name) {
+            fs::remove_dir_all(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Stable 64 bit FNV-1a hash, the same across runs and builds
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Load a patch, None if it isn't cached or the file is stale
+pub fn load(key: &PatchKey) -> Option<Mesh> {
+    let mut bytes = vec![];
+    fs::File::open(key.file())
+        .ok()?
+        .read_to_end(&mut bytes)
+        .ok()?;
+    let mut reader = Reader(&bytes);
+
+    if reader.take(8)? != MAGIC
+        || reader.u32()? != VERSION
+        || reader.u32()? != key.seed
+        || reader.u64()? != key.params
+        || reader.u8()? as usize != key.address.face
+    {
+        return None;
+    }
+    let path_len = reader.u8()? as usize;
+    if reader.take(path_len)? != key.address.path {
+        return None;
+    }
+    let error = f32::from_bits(reader.u32()?);
+    let mut mesh = Mesh {
+        vertices: reader.f32s()?,
+        normals: reader.f32s()?,
+        texture_coordinates: reader.f32s()?,
+        colors: reader.f32s()?,
+        parent_heights: reader.f32s()?,
+        tangents: reader.f32s()?,
+        biomes: reader.u32s()?,
+        indices: reader.u32s()?,
+        error,
+        ..Default::default()
+    };
+    mesh.index_count = mesh.indices.len() as i32;
+    Some(mesh)
+}
+
+/// Store a patch. Written to a temporary file first, so a reader never sees
+/// half a patch.
+pub fn store(key: &PatchKey, mesh: &Mesh) -> io::Result<()> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&key.seed.to_le_bytes());
+    bytes.extend_from_slice(&key.params.to_le_bytes());
+    bytes.push(key.address.face as u8);
+    bytes.push(key.address.path.len() as u8);
+    bytes.extend_from_slice(&key.address.path);
+    bytes.extend_from_slice(&mesh.error.to_le_bytes());
+    for array in [
+        &mesh.vertices,
+        &mesh.normals,
+        &mesh.texture_coordinates,
+        &mesh.colors,
+        &mesh.parent_heights,
+        &mesh.tangents,
+    ] {
+        bytes.extend_from_slice(&(array.len() as u32).to_le_bytes());
+        array
+            .iter()
+            .for_each(|v| bytes.extend_from_slice(&v.to_le_bytes()));
+    }
+    for array in [&mesh.biomes, &mesh.indices] {
+        bytes.extend_from_slice(&(array.len() as u32).to_le_bytes());
+        array
+            .iter()
+            .for_each(|v| bytes.extend_from_slice(&v.to_le_bytes()));
+    }
+
+    let file = key.file();
+    fs::create_dir_all(file.parent().unwrap())?;
+    let n = TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed);
+    let temporary = file.with_extension(format!("{}-{}.tmp", std::process::id(), n));
+    fs::File::create(&temporary)?.write_all(&bytes)?;
+    fs::rename(&temporary, &file)
+}
+
+/// Reads little endian values from the front of a byte slice
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+    fn u32s(&mut self) -> Option<Vec<u32>> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len.checked_mul(4)?)?;
+        Some(
+            bytes
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .collect(),
+        )
+    }
+    fn f32s(&mut self) -> Option<Vec<f32>> {
+        Some(self.u32s()?.into_iter().map(f32::from_bits).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Key in a directory of its own, removed when dropped
+    struct TestCache(PatchKey);
+
+    impl TestCache {
+        fn new(name: &str) -> Self {
+            let directory = std::env::temp_dir().join(format!(
+                "procedural-planets-{}-{}",
+                name,
+                std::process::id()
+            ));
+            TestCache(PatchKey {
+                directory,
+                seed: 7,
+                params: 0x1234,
+                address: PatchAddress::root(2).child(1).child(3),
+            })
+        }
+    }
+
+    impl Drop for TestCache {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.directory);
+        }
+    }
+
+    fn patch() -> Mesh {
+        Mesh {
+            vertices: vec![0.0, 1.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 1.0],
+            normals: vec![0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            parent_heights: vec![0.5, -0.25, 0.0],
+            biomes: vec![1, 2, 3],
+            indices: vec![0, 2, 1],
+            index_count: 3,
+            error: 0.125,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn stored_patch_loads_back() {
+        let cache = TestCache::new("round-trip");
+        let mesh = patch();
+        store(&cache.0, &mesh).unwrap();
+        let loaded = load(&cache.0).unwrap();
+        assert_eq!(loaded.vertices, mesh.vertices);
+        assert_eq!(loaded.normals, mesh.normals);
+        assert_eq!(loaded.parent_heights, mesh.parent_heights);
+        assert_eq!(loaded.biomes, mesh.biomes);
+        assert_eq!(loaded.indices, mesh.indices);
+        assert_eq!(loaded.index_count, mesh.index_count);
+        assert_eq!(loaded.error, mesh.error);
+    }
+
+    #[test]
+    fn other_version_is_stale() {
+        let cache = TestCache::new("version");
+        store(&cache.0, &patch()).unwrap();
+        let file = cache.0.file();
+        let mut bytes = fs::read(&file).unwrap();
+        bytes[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&file, bytes).unwrap();
+        assert!(load(&cache.0).is_none());
+    }
+
+    #[test]
+    fn other_params_are_stale() {
+        let cache = TestCache::new("params");
+        store(&cache.0, &patch()).unwrap();
+        let other = PatchKey {
+            params: 0x5678,
+            ..cache.0.clone()
+        };
+        assert_ne!(other.file().parent(), cache.0.file().parent());
+        assert!(load(&other).is_none());
+        // Even where the file is found, the header tells the params apart
+        fs::create_dir_all(other.file().parent().unwrap()).unwrap();
+        fs::copy(cache.0.file(), other.file()).unwrap();
+        assert!(load(&other).is_none());
+    }
+
+    #[test]
+    fn prune_removes_other_planets_only() {
+        let cache = TestCache::new("prune");
+        store(&cache.0, &patch()).unwrap();
+        let other = PatchKey {
+            params: 0x5678,
+            ..cache.0.clone()
+        };
+        store(&other, &patch()).unwrap();
+        let unrelated = cache.0.directory.join("notes");
+        fs::create_dir_all(&unrelated).unwrap();
+
+        let keep = [(cache.0.seed, cache.0.params)];
+        assert_eq!(prune(&cache.0.directory, &keep).unwrap(), 1);
+        assert!(load(&cache.0).is_some());
+        assert!(!other.file().parent().unwrap().exists());
+        assert!(unrelated.exists());
+        // Nothing cached yet is nothing to prune
+        assert_eq!(prune(&cache.0.directory.join("missing"), &keep).unwrap(), 0);
+    }
+}
@@ -0,0 +1,266 @@
+use nalgebra_glm as glm;
+use rand::{Rng, SeedableRng};
+
+use crate::cubesphere::{self, CubeMap};
+
+//-----------------------------------------------------------------------------/
+// Hydraulic erosion
+//-----------------------------------------------------------------------------/
+// Droplet based erosion, as described by Hans Theobald Beyer in
+// "Implementation of a method for hydraulic erosion" and popularised by
+// Sebastian Lague. Droplets move by their direction on the sphere rather than
+// on a single face, so they carry on across the cube face edges.
+
+/// Parameters for the hydraulic erosion pass
+#[derive(Debug, Copy, Clone)]
+pub struct ErosionParams {
+    pub enabled: bool,
+    pub resolution: usize,   // Texels along each side of a cube face
+    pub droplets: usize,     // Number of droplets simulated
+    pub max_lifetime: usize, // Maximum steps for a droplet
+    pub inertia: f32,        // How much a droplet keeps its direction, [0, 1]
+    pub capacity: f32,       // Sediment carried per speed, water and slope
+    pub min_capacity: f32,   // Carried even on flat ground
+    pub erode_speed: f32,    // Fraction of free capacity eroded per step
+    pub deposit_speed: f32,  // Fraction of excess sediment deposited per step
+    pub evaporate_speed: f32,
+    pub gravity: f32,
+    pub radius: usize, // Radius of the erosion brush, in texels
+}
+
+impl Default for ErosionParams {
+    fn default() -> Self {
+        ErosionParams {
+            enabled: false,
+            resolution: 128,
+            droplets: 40000,
+            max_lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_capacity: 0.01,
+            erode_speed: 0.3,
+            deposit_speed: 0.3,
+            evaporate_speed: 0.01,
+            gravity: 4.0,
+            radius: 2,
+        }
+    }
+}
+
+/// Parameters for the thermal erosion pass
+#[derive(Debug, Copy, Clone)]
+pub struct ThermalParams {
+    pub enabled: bool,
+    pub iterations: usize,
+    pub talus_angle: f32, // Steepest stable slope, in radians
+    pub rate: f32,        // Fraction of the excess slope moved per iteration
+}
+
+impl Default for ThermalParams {
+    fn default() -> Self {
+        ThermalParams {
+            enabled: false,
+            iterations: 50,
+            talus_angle: 0.3,
+            rate: 0.5,
+        }
+    }
+}
+
+/// Run hydraulic erosion on a height map, droplets placed from `seed`
+pub fn hydraulic(map: &mut CubeMap, params: &ErosionParams, seed: u32) {
+    // Work in texel units, so slopes are comparable to a flat grid
+    let step = map.texel_angle();
+    map.data.iter_mut().for_each(|h| *h /= step);
+
+    // Brush offsets in texels and their weights
+    let r = params.radius as i32;
+    let mut brush = vec![];
+    for y in -r..=r {
+        for x in -r..=r {
+            let d = ((x * x + y * y) as f32).sqrt();
+            if d <= r as f32 {
+                brush.push((x as f32, y as f32, 1.0 - d / (r as f32 + 1.0)));
+            }
+        }
+    }
+    let weight_sum: f32 = brush.iter().map(|b| b.2).sum();
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed as u64);
+    for _ in 0..params.droplets {
+        let mut pos = glm::normalize(&glm::vec3(
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0f32..1.0),
+        ));
+        let mut dir: glm::Vec3 = glm::zero();
+        let mut speed = 1.0;
+        let mut water = 1.0;
+        let mut sediment = 0.0;
+
+        for _ in 0..params.max_lifetime {
+            let (height, gradient) = height_gradient(map, &pos, step);
+            // Keep some of the old direction, projected onto the new tangent plane
+            dir = dir * params.inertia - gradient * (1.0 - params.inertia);
+            dir -= pos * glm::dot(&dir, &pos);
+            if glm::length(&dir) < 1e-6 {
+                break;
+            }
+            dir = glm::normalize(&dir);
+            let next = glm::normalize(&(pos + dir * step));
+
+            let delta_height = map.sample(&next) - height;
+            let capacity =
+                (-delta_height * speed * water * params.capacity).max(params.min_capacity);
+
+            if sediment > capacity || delta_height > 0.0 {
+                // Fill the pit uphill, or drop what can't be carried
+                let amount = if delta_height > 0.0 {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - capacity) * params.deposit_speed
+                };
+                sediment -= amount;
+                map.splat(&pos, amount);
+            } else {
+                // Never dig deeper than the step down, to avoid spikes
+                let amount = ((capacity - sediment) * params.erode_speed).min(-delta_height);
+                let (t1, t2) = cubesphere::tangent_basis(&pos);
+                for &(x, y, w) in &brush {
+                    let p = glm::normalize(&(pos + (t1 * x + t2 * y) * step));
+                    map.splat(&p, -amount * w / weight_sum);
+                }
+                sediment += amount;
+            }
+
+            speed = (speed * speed - delta_height * params.gravity)
+                .max(0.0)
+                .sqrt();
+            water *= 1.0 - params.evaporate_speed;
+            pos = next;
+        }
+    }
+
+    map.data.iter_mut().for_each(|h| *h *= step);
+}
+
+/// Height and its gradient along the surface, by central differences
+fn height_gradient(map: &CubeMap, pos: &glm::Vec3, step: f32) -> (f32, glm::Vec3) {
+    let (t1, t2) = cubesphere::tangent_basis(pos);
+    let e = step * 0.5;
+    let h = |t: &glm::Vec3, s: f32| map.sample(&glm::normalize(&(pos + t * s)));
+    let g1 = (h(&t1, e) - h(&t1, -e)) / (2.0 * e) * step;
+    let g2 = (h(&t2, e) - h(&t2, -e)) / (2.0 * e) * step;
+    (map.sample(pos), t1 * g1 + t2 * g2)
+}
+
+//-----------------------------------------------------------------------------/
+// Thermal erosion
+//-----------------------------------------------------------------------------/
+// Material slides from a texel to its lower neighbours wherever the slope is
+// steeper than the talus angle, building scree slopes and flat valley floors.
+// All texels are updated together each iteration, so the result only depends
+// on the height map and the parameters.
+
+/// Run thermal erosion on a height map
+pub fn thermal(map: &mut CubeMap, params: &ThermalParams) {
+    // Work in texel units, so a slope is a height difference
+    let step = map.texel_angle();
+    map.data.iter_mut().for_each(|h| *h /= step);
+    let talus = params.talus_angle.tan();
+
+    // Edge texels are only handled once, copies are updated by `add`
+    let texels = (0..map.data.len())
+        .filter(|&idx| map.canonical(idx) == idx)
+        .map(|idx| (idx, map.neighbours(idx)))
+        .collect::<Vec<_>>();
+    let mut delta = vec![0.0; map.data.len()];
+
+    for _ in 0..params.iterations {
+        delta.iter_mut().for_each(|d| *d = 0.0);
+        for (idx, neighbours) in &texels {
+            let h = map.data[*idx];
+            let excess = neighbours.map(|n| (h - map.data[n] - talus).max(0.0));
+            let total: f32 = excess.iter().sum();
+            if total <= 0.0 {
+                continue;
+            }
+            // Move half the steepest excess, shared by how much steeper
+            // each neighbour is than the talus angle
+            let moved = params.rate * excess.iter().fold(0.0f32, |a, &b| a.max(b)) / 2.0;
+            delta[*idx] -= moved;
+            for (n, e) in neighbours.iter().zip(excess) {
+                delta[*n] += moved * e / total;
+            }
+        }
+        for (idx, _) in &texels {
+            if delta[*idx] != 0.0 {
+                map.add(*idx, delta[*idx]);
+            }
+        }
+    }
+
+    map.data.iter_mut().for_each(|h| *h *= step);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sharp peaks, much steeper than the talus angle
+    fn peaks() -> CubeMap {
+        CubeMap::from_fn(16, |dir| {
+            0.2 * (dir.x * 9.0).sin().abs() * (dir.z * 7.0).cos()
+        })
+    }
+
+    /// Total height over texels, counting each edge texel once
+    fn mass(map: &CubeMap) -> f64 {
+        (0..map.data.len())
+            .filter(|&idx| map.canonical(idx) == idx)
+            .map(|idx| map.data[idx] as f64)
+            .sum()
+    }
+
+    /// Steepest height difference between neighbouring texels
+    fn max_slope(map: &CubeMap) -> f32 {
+        (0..map.data.len())
+            .flat_map(|idx| map.neighbours(idx).map(|n| map.data[idx] - map.data[n]))
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn thermal_is_deterministic() {
+        let params = ThermalParams {
+            enabled: true,
+            ..Default::default()
+        };
+        let (mut a, mut b) = (peaks(), peaks());
+        thermal(&mut a, &params);
+        thermal(&mut b, &params);
+        assert_eq!(a.data, b.data);
+    }
+
+    #[test]
+    fn thermal_keeps_mass_and_flattens_slopes() {
+        let params = ThermalParams {
+            enabled: true,
+            ..Default::default()
+        };
+        let mut map = peaks();
+        let before = (mass(&map), max_slope(&map));
+        thermal(&mut map, &params);
+        let after = (mass(&map), max_slope(&map));
+        assert!(
+            (before.0 - after.0).abs() < 1e-5,
+            "mass {} became {}",
+            before.0,
+            after.0
+        );
+        assert!(after.1 < before.1, "slope {} became {}", before.1, after.1);
+        // Edge texels and their copies still agree
+        for idx in 0..map.data.len() {
+            assert_eq!(map.data[idx], map.data[map.canonical(idx)]);
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::mesh::Mesh;
+use crate::procedural_planet::Planet;
+use crate::{cubesphere, scene, util};
+
+//-----------------------------------------------------------------------------/
+// Export
+//-----------------------------------------------------------------------------/
+// Meshes written to files for Blender and other tools, as Wavefront OBJ,
+// binary PLY or glTF 2.0, chosen by the file extension. Positions, normals and
+// vertex colours are written, normals and colours only if the mesh has them.
+// Nothing here needs a GL context, so planets can be exported headless with
+// `--export`, see `run`.
+
+/// File formats meshes can be exported to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Obj,  // Wavefront OBJ, colours after the positions
+    Ply,  // Binary little endian PLY
+    Gltf, // glTF 2.0 with the buffer embedded
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "obj" => Some(Format::Obj),
+            "ply" => Some(Format::Ply),
+            "gltf" => Some(Format::Gltf),
+            _ => None,
+        }
+    }
+}
+
+/// Write a mesh to a file, in the format given by its extension
+pub fn write(mesh: &Mesh, path: &Path) -> io::Result<()> {
+    let format = Format::from_path(path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not .obj, .ply or .gltf", path.display()),
+        )
+    })?;
+    let mut file = BufWriter::new(File::create(path)?);
+    match format {
+        Format::Obj => write_obj(mesh, &mut file)?,
+        Format::Ply => write_ply(mesh, &mut file)?,
+        Format::Gltf => write_gltf(mesh, &mut file)?,
+    }
+    file.flush()
+}
+
+/// Whether the mesh has normals and colours for all vertices
+fn attributes(mesh: &Mesh) -> (bool, bool) {
+    let count = mesh.vertex_count();
+    (
+        count > 0 && mesh.normals.len() == count * 3,
+        count > 0 && mesh.colors.len() == count * 4,
+    )
+}
+
+pub fn write_obj(mesh: &Mesh, out: &mut impl Write) -> io::Result<()> {
+    let (has_normals, has_colors) = attributes(mesh);
+    writeln!(out, "# Procedural planets")?;
+    for (i, v) in mesh.vertices.chunks_exact(3).enumerate() {
+        write!(out, "v {} {} {}", v[0], v[1], v[2])?;
+        if has_colors {
+            let c = &mesh.colors[i * 4..i * 4 + 3];
+            write!(out, " {} {} {}", c[0], c[1], c[2])?;
+        }
+        writeln!(out)?;
+    }
+    if has_normals {
+        for n in mesh.normals.chunks_exact(3) {
+            writeln!(out, "vn {} {} {}", n[0], n[1], n[2])?;
+        }
+    }
+    // Indices start at 1, normals share the vertex index
+    for t in mesh.indices.chunks_exact(3) {
+        let [a, b, c] = [t[0] + 1, t[1] + 1, t[2] + 1];
+        if has_normals {
+            writeln!(out, "f {a}//{a} {b}//{b} {c}//{c}")?;
+        } else {
+            writeln!(out, "f {a} {b} {c}")?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_ply(mesh: &Mesh, out: &mut impl Write) -> io::Result<()> {
+    let (has_normals, has_colors) = attributes(mesh);
+    let triangles = mesh.indices.len() / 3;
+    let mut header = String::from("ply\nformat binary_little_endian 1.0\n");
+    header += "comment Procedural planets\n";
+    header += &format!("element vertex {}\n", mesh.vertex_count());
+    for name in ["x", "y", "z"] {
+        header += &format!("property float {}\n", name);
+    }
+    if has_normals {
+        for name in ["nx", "ny", "nz"] {
+            header += &format!("property float {}\n", name);
+        }
+    }
+    if has_colors {
+        for name in ["red", "green", "blue", "alpha"] {
+            header += &format!("property uchar {}\n", name);
+        }
+    }
+    header += &format!("element face {}\n", triangles);
+    header += "property list uchar uint vertex_indices\nend_header\n";
+    out.write_all(header.as_bytes())?;
+
+    for i in 0..mesh.vertex_count() {
+        for v in &mesh.vertices[i * 3..i * 3 + 3] {
+            out.write_all(&v.to_le_bytes())?;
+        }
+        if has_normals {
+            for n in &mesh.normals[i * 3..i * 3 + 3] {
+                out.write_all(&n.to_le_bytes())?;
+            }
+        }
+        if has_colors {
+            for c in &mesh.colors[i * 4..i * 4 + 4] {
+                out.write_all(&[(c.clamp(0.0, 1.0) * 255.0).round() as u8])?;
+            }
+        }
+    }
+    for t in mesh.indices.chunks_exact(3) {
+        out.write_all(&[3])?;
+        for i in t {
+            out.write_all(&i.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// glTF 2.0 as a single file, the buffer in a base64 data URI. Attributes
+/// follow each other in the buffer, then the indices.
+pub fn write_gltf(mesh: &Mesh, out: &mut impl Write) -> io::Result<()> {
+    let (has_normals, has_colors) = attributes(mesh);
+    let (min, max) = mesh.bounding_box().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "glTF needs at least one vertex",
+        )
+    })?;
+    let count = mesh.vertex_count();
+    let indices = mesh.indices.len() / 3 * 3;
+
+    // Buffer views and accessors, with the name of the attribute they hold
+    const ARRAY_BUFFER: u32 = 34962;
+    const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+    const FLOAT: u32 = 5126;
+    const UNSIGNED_INT: u32 = 5125;
+    let mut buffer: Vec<u8> = vec![];
+    let mut views = vec![];
+    let mut accessors = vec![];
+    let mut attributes = vec![];
+    let mut add = |name: &str, bytes: Vec<u8>, kind: &str, component: u32, count: usize| {
+        let target = if name.is_empty() {
+            ELEMENT_ARRAY_BUFFER
+        } else {
+            ARRAY_BUFFER
+        };
+        views.push(format!(
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#,
+            buffer.len(),
+            bytes.len(),
+            target
+        ));
+        let bounds = if name == "POSITION" {
+            format!(
+                r#","min":[{},{},{}],"max":[{},{},{}]"#,
+                min.x, min.y, min.z, max.x, max.y, max.z
+            )
+        } else {
+            String::new()
+        };
+        accessors.push(format!(
+            r#"{{"bufferView":{},"componentType":{},"count":{},"type":"{}"{}}}"#,
+            views.len() - 1,
+            component,
+            count,
+            kind,
+            bounds
+        ));
+        if !name.is_empty() {
+            attributes.push(format!(r#""{}":{}"#, name, accessors.len() - 1));
+        }
+        buffer.extend(bytes);
+    };
+    let floats = |array: &[f32]| array.iter().flat_map(|v| v.to_le_bytes()).collect();
+    add(
+        "POSITION",
+        floats(&mesh.vertices[..count * 3]),
+        "VEC3",
+        FLOAT,
+        count,
+    );
+    if has_normals {
+        add("NORMAL", floats(&mesh.normals), "VEC3", FLOAT, count);
+    }
+    if has_colors {
+        add("COLOR_0", floats(&mesh.colors), "VEC4", FLOAT, count);
+    }
+    let index_bytes = mesh.indices[..indices]
+        .iter()
+        .flat_map(|i| i.to_le_bytes())
+        .collect();
+    add("", index_bytes, "SCALAR", UNSIGNED_INT, indices);
+    let index_accessor = accessors.len() - 1;
+
+    write!(
+        out,
+        concat!(
+            r#"{{"asset":{{"version":"2.0","generator":"Procedural planets"}},"#,
+            r#""scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0}}],"#,
+            r#""meshes":[{{"primitives":[{{"attributes":{{{}}},"indices":{},"mode":4}}]}}],"#,
+            r#""buffers":[{{"byteLength":{},"uri":"data:application/octet-stream;base64,{}"}}],"#,
+            r#""bufferViews":[{}],"accessors":[{}]}}"#,
+        ),
+        attributes.join(","),
+        index_accessor,
+        buffer.len(),
+        base64(&buffer),
+        views.join(","),
+        accessors.join(","),
+    )?;
+    writeln!(out)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+//-----------------------------------------------------------------------------/
+// Headless export
+//-----------------------------------------------------------------------------/
+
+const USAGE: &str =
+    "Usage: procedural-planets --export <file.obj|file.ply|file.gltf> [planet] [level] [triangles]
+  planet     index of the planet in scene.rs, 1 by default
+  level      level of detail of every patch, 2 by default
+  triangles  simplify to at most this many triangles, 0 to keep all, by default";
+
+/// Export a planet of the scene from the command line arguments after
+/// `--export`, without opening a window
+pub fn run(args: &[String]) {
+    let parse = |i: usize, default: usize| match args.get(i) {
+        Some(arg) => arg.parse().ok(),
+        None => Some(default),
+    };
+    let (Some(path), Some(planet_index), Some(level), Some(triangles)) =
+        (args.first(), parse(1, 1), parse(2, 2), parse(3, 0))
+    else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    let path = Path::new(path);
+    if Format::from_path(path).is_none() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+
+    let conf = util::Config::load();
+    cubesphere::set_projection(conf.cube_projection);
+    let timer = std::time::SystemTime::now();
+    eprint!("Creating planets . . . ");
+    let (planets, _, _) = scene::create_scene(&conf);
+    eprintln!("took {:?}", timer.elapsed().unwrap());
+    let planet: &Planet = match planets.get(planet_index) {
+        Some(planet) => planet,
+        None => {
+            eprintln!(
+                "No planet {}, the scene has {}",
+                planet_index,
+                planets.len()
+            );
+            std::process::exit(2);
+        }
+    };
+
+    let timer = std::time::SystemTime::now();
+    eprint!("Generating terrain at level {} . . . ", level);
+    let mut mesh = planet.uniform_mesh(level);
+    eprintln!("took {:?}", timer.elapsed().unwrap());
+    if triangles > 0 {
+        let timer = std::time::SystemTime::now();
+        eprint!("Simplifying to {} triangles . . . ", triangles);
+        // Welded, so the whole planet is one surface without boundaries
+        let error = mesh.simplify(triangles, f32::INFINITY);
+        eprintln!(
+            "took {:?}, moved the surface up to {}",
+            timer.elapsed().unwrap(),
+            error
+        );
+    }
+    match write(&mesh, path) {
+        Ok(()) => eprintln!(
+            "Wrote {} vertices and {} triangles to {}",
+            mesh.vertex_count(),
+            mesh.indices.len() / 3,
+            path.display()
+        ),
+        Err(e) => {
+            eprintln!("Could not export to {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single triangle facing up, coloured red
+    fn triangle() -> Mesh {
+        Mesh {
+            vertices: vec![0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0],
+            normals: [0.0, 1.0, 0.0].repeat(3),
+            colors: [1.0, 0.0, 0.0, 1.0].repeat(3),
+            indices: vec![0, 1, 2],
+            index_count: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn formats_from_extension() {
+        assert_eq!(Format::from_path(Path::new("a.OBJ")), Some(Format::Obj));
+        assert_eq!(Format::from_path(Path::new("a/b.ply")), Some(Format::Ply));
+        assert_eq!(Format::from_path(Path::new("a.gltf")), Some(Format::Gltf));
+        assert_eq!(Format::from_path(Path::new("a.glb")), None);
+        assert_eq!(Format::from_path(Path::new("obj")), None);
+    }
+
+    #[test]
+    fn obj_has_colours_normals_and_faces() {
+        let mut out = vec![];
+        write_obj(&triangle(), &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "v 0 0 0 1 0 0");
+        assert_eq!(lines[4], "vn 0 1 0");
+        assert_eq!(lines[7], "f 1//1 2//2 3//3");
+        assert_eq!(lines.len(), 8);
+    }
+
+    #[test]
+    fn ply_header_matches_body() {
+        let mut out = vec![];
+        write_ply(&triangle(), &mut out).unwrap();
+        let end = b"end_header\n";
+        let body = out.windows(end.len()).position(|w| w == end).unwrap() + end.len();
+        let header = String::from_utf8(out[..body].to_vec()).unwrap();
+        assert!(header.contains("element vertex 3\n"));
+        assert!(header.contains("element face 1\n"));
+        // Position, normal and colour per vertex, then a count and indices
+        assert_eq!(out.len() - body, 3 * (12 + 12 + 4) + 1 + 12);
+        assert_eq!(&out[body + 24..body + 28], &[255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn gltf_buffer_holds_every_accessor() {
+        let mut out = vec![];
+        write_gltf(&triangle(), &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains(r#""version":"2.0""#));
+        assert!(text.contains(r#""attributes":{"POSITION":0,"NORMAL":1,"COLOR_0":2},"indices":3"#));
+        assert!(text.contains(r#""min":[0,0,0],"max":[1,0,1]"#));
+        let length = 36 + 36 + 48 + 12;
+        assert!(text.contains(&format!(r#""byteLength":{},"uri""#, length)));
+        assert!(write_gltf(&Mesh::default(), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn base64_pads_the_last_group() {
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b""), "");
+    }
+
+    /// A planet is generated and joined without a GL context, into a closed
+    /// surface with outward normals
+    #[test]
+    fn planet_exports_headless_and_closed() {
+        let mut planet = Planet::with_seed(1);
+        planet.max_height = 0.05;
+        planet.bake_terrain();
+        let mesh = planet.uniform_mesh(0);
+        assert!(mesh.validate().is_ok());
+
+        let triangles = mesh.indices.len() / 3;
+        assert_eq!(triangles, 6 * 16 * 16 * 2);
+        // Euler characteristic of a sphere, every edge shared by two triangles
+        assert_eq!(mesh.vertex_count(), 2 + triangles / 2);
+        assert_eq!(mesh.colors.len(), mesh.vertex_count() * 4);
+        for (v, n) in mesh
+            .vertices
+            .chunks_exact(3)
+            .zip(mesh.normals.chunks_exact(3))
+        {
+            assert!(v[0] * n[0] + v[1] * n[1] + v[2] * n[2] > 0.0);
+        }
+    }
+
+    #[test]
+    fn planet_simplifies_for_export() {
+        let mut planet = Planet::with_seed(1);
+        planet.max_height = 0.05;
+        planet.bake_terrain();
+        let mut mesh = planet.uniform_mesh(0);
+        mesh.simplify(1000, f32::INFINITY);
+        assert!(mesh.validate().is_ok());
+
+        // Still closed, and the vertices left keep their attributes
+        let triangles = mesh.indices.len() / 3;
+        assert!(triangles <= 1000);
+        assert_eq!(mesh.vertex_count(), 2 + triangles / 2);
+        assert_eq!(mesh.normals.len(), mesh.vertex_count() * 3);
+        assert_eq!(mesh.colors.len(), mesh.vertex_count() * 4);
+    }
+}
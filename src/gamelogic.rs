@@ -32,6 +32,7 @@ pub fn game(
     // Read config
     //-------------------------------------------------------------------------/
     let mut conf = util::Config::load();
+    cubesphere::set_projection(conf.cube_projection);
 
     let mut player = player::Player {
         height: conf.player_height,
@@ -146,6 +147,14 @@ pub fn game(
     text_gfxmem_node.position = glm::vec3(-1.0, -1.0 + text_scale * 0.05 * 6.0, 0.0);
     text_gfxmem_node.scale = glm::vec3(1.0, 1.0, 1.0) * text_scale;
 
+    #[allow(unused_assignments)]
+    let mut text_culled_mesh = mesh::Mesh::text_buffer("N/A", 49.0 / 29.0, 1.0);
+    let mut text_culled_node = SceneNode::from_vao(unsafe { text_culled_mesh.mkvao() });
+    text_culled_node.node_type = SceneNodeType::Geometry2d;
+    text_culled_node.texture_id = Some(charmap_id);
+    text_culled_node.position = glm::vec3(-1.0, -1.0 + text_scale * 0.05 * 7.0, 0.0);
+    text_culled_node.scale = glm::vec3(1.0, 1.0, 1.0) * text_scale;
+
     let controls_text = [
         "WSAD/SHIFT/SPACE : movement",
         "UP/DOWN : increase and decrease movement speed",
@@ -181,7 +190,7 @@ pub fn game(
     //-------------------------------------------------------------------------/
     // Scene setup, build planets
     //-------------------------------------------------------------------------/
-    let (mut planets, mut planet_nodes, lightsources) = scene::create_scene();
+    let (mut planets, mut planet_nodes, lightsources) = scene::create_scene(&conf);
     //-------------------------------------------------------------------------/
     // Organize planets and nodes
     //-------------------------------------------------------------------------/
@@ -211,6 +220,7 @@ pub fn game(
     gui_root.add_child(&text_height_node);
     gui_root.add_child(&text_mouse_node);
     gui_root.add_child(&text_gfxmem_node);
+    gui_root.add_child(&text_culled_node);
     controls_text.for_each(|nd| gui_root.add_child(&nd));
 
 
@@ -222,6 +232,13 @@ pub fn game(
 
     let mut key_debounce: HashMap<VirtualKeyCode, u32> = HashMap::new();
     let mut frame_counter: u64 = 0;
+    let mut cull_count = planet::CullCount::default();
+
+    // Budgets for generated terrain, configured in MiB
+    let mut patch_cache = patch_cache::PatchCache::new(
+        (conf.patch_gpu_budget * 1024.0 * 1024.0) as u64,
+        (conf.patch_cpu_budget * 1024.0 * 1024.0) as u64,
+    );
 
 
     //-------------------------------------------------------------------------/
@@ -235,6 +252,15 @@ pub fn game(
         setup_timer.elapsed().unwrap()
     );
     let mut scaled = true;
+    // Camera the terrain was last drawn for, the ground under the player
+    // morphs as it does on screen
+    let mut lod_view = planet::LodView::new(
+        player.position,
+        conf.fov,
+        context.window().inner_size().height as f32,
+        conf.lod_pixel_error,
+        frame_counter,
+    );
 
     loop {
         let now = std::time::Instant::now();
@@ -360,6 +386,7 @@ pub fn game(
                 &mut key_debounce,
                 &mut player,
                 &planets[cpid],
+                &|position| ground_height(&planets[cpid], &planet_nodes[cpid], &lod_view, position),
                 &mut conf,
                 delta_time,
             );
@@ -382,13 +409,21 @@ pub fn game(
         text_pos_node.update_buffers(&text_pos_mesh);
         // Log gpu memory
         let buf_mem = util::MEMORY_USAGE.load(std::sync::atomic::Ordering::Relaxed);
-        let s = format!("GPU mem {}KiB used for planet buffers",
+        let s = format!("GPU mem {}KiB used for buffers",
             buf_mem / 1024);
         text_gfxmem_mesh = mesh::Mesh::text_buffer(
             &s,
             49.0 / 29.0, 1.0 * s.len() as f32 / 28.0
         );
         text_gfxmem_node.update_buffers(&text_gfxmem_mesh);
+        // Log culled terrain patches, from last frame
+        let s = format!("Patches drawn: {}, culled by frustum: {}, by horizon: {}",
+            cull_count.drawn, cull_count.frustum, cull_count.horizon);
+        text_culled_mesh = mesh::Mesh::text_buffer(
+            &s,
+            49.0 / 29.0, 1.0 * s.len() as f32 / 28.0
+        );
+        text_culled_node.update_buffers(&text_culled_mesh);
         // Log movement speed
         let s = format!("Speed: {:.3}", conf.movement_speed);
         text_mspeed_mesh = mesh::Mesh::text_buffer(
@@ -415,14 +450,18 @@ pub fn game(
             49.0 / 29.0, 1.0 * s.len() as f32 / 28.0
         );
         text_mouse_node.update_buffers(&text_mouse_mesh);
-        // Display player state
+        // Display player state, and any water under a landed player
+        let feet = player.feet();
         let s = match player.state {
             player::PlayerState::FreeFloat => String::from("Free floating"),
             player::PlayerState::Anchored(a) => String::from(
                 &format!("Anchored to: {:.3},{:.3},{:.3}", a.x, a.y, a.z)
             ),
             player::PlayerState::Landed(a) => String::from(
-                &format!("Landed on: {:.3},{:.3},{:.3}", a.x, a.y, a.z)
+                &format!("Landed on: {:.3},{:.3},{:.3}, river: {:.2}, lake: {:.2}", a.x, a.y, a.z,
+                    planets[player.closest_planet_id].get_river(&feet),
+                    planets[player.closest_planet_id].get_lake(&feet),
+                )
             ),
         };
         text_pstate_mesh = mesh::Mesh::text_buffer(
@@ -538,8 +577,15 @@ pub fn game(
                 player.closest_planet_id = planets_sorted[0].1;
             }
             // Stop rendering passed render_limit
+            lod_view = planet::LodView::new(
+                player.position,
+                conf.fov,
+                wsize.height as f32,
+                conf.lod_pixel_error,
+                frame_counter,
+            );
             (0..planets.len()).for_each(|i| {
-                planets[i].lod(&mut (*planet_nodes[i]), player.position);
+                planets[i].lod(&mut (*planet_nodes[i]), &lod_view);
                 let depth_test = planets[i].radius / glm::length(&(planets[i].position - player.position));
                 planet_nodes[i].node_type = if depth_test.atan() < conf.render_limit {
                     SceneNodeType::PlanetSkip
@@ -547,6 +593,14 @@ pub fn game(
                     SceneNodeType::Empty
                 };
             });
+            // Free terrain that hasn't been used for a while
+            patch_cache.evict(&planet_nodes, frame_counter);
+            // Skip terrain that can't be seen
+            let frustum = util::frustum_planes(&perspective_view);
+            cull_count = planet::CullCount::default();
+            for i in 0..planets.len() {
+                planets[i].cull(&mut planet_nodes[i], &frustum, &player.position, &mut cull_count);
+            }
 
             gl::Uniform1ui(
                 sh.get_uniform_location("u_planets_len"),
@@ -567,6 +621,7 @@ pub fn game(
                 1,
                 player.position.as_ptr()
             );
+            lod_view.update_uniforms(&sh);
 
             //-----------------------------------------------------------------/
             // Draw skybox
@@ -705,6 +760,7 @@ fn keyboard_input(
     key_debounce: &mut std::collections::HashMap<glutin::event::VirtualKeyCode, u32>,
     player: &mut player::Player,
     closest_planet: &planet::Planet,
+    ground_height: &dyn Fn(&glm::Vec3) -> f64,
     conf: &mut util::Config,
     delta_time: f32
 ) {
@@ -716,6 +772,7 @@ fn keyboard_input(
     let mut player_position = player.position - up * player.height;
     let mut position = player_position;
     let movement_speed = conf.movement_speed;
+    let mut jump = false;
     for key in keys.iter() {
         match key {
             /* Move left/right */
@@ -751,17 +808,8 @@ fn keyboard_input(
             /* Move up/down */
             VirtualKeyCode::Space => {
                 match player.state {
-                    Landed(_) => {
-                        // Jump, set horizontal speed
-                        let planet_h = closest_planet.get_height(&position);
-                        let player_h = glm::length(&(
-                            player.feet() - closest_planet.position
-                        )); // closest_planet.position == a
-                        // Not quite right, but jetpack physics is alright as well
-                        if planet_h - player_h < H_ERROR {
-                            player.hspeed = conf.jump_speed;
-                        }
-                    },
+                    // Jump once the ground height is known, below
+                    Landed(_) => jump = true,
                     _ => position += up * delta_time * movement_speed,
                 }
             },
@@ -828,14 +876,40 @@ fn keyboard_input(
             player.hspeed -= delta_time * closest_planet.gravity;
         }
     }
-    let height = (100.0 * closest_planet.get_height(&position)).round() / 100.0;
-    let go_to = (100.0 * glm::length(&(position - closest_planet.position))).round() / 100.0;
+    // Compare in double precision, so walking on the ground doesn't jitter.
+    // Looking the mesh up is costly, once per frame is enough
+    let height = ground_height(&position);
+    if jump {
+        // Jump, set horizontal speed, it takes off next frame
+        let player_h = glm::length(&(
+            player.feet() - closest_planet.position
+        )); // closest_planet.position == a
+        // Not quite right, but jetpack physics is alright as well
+        if height as f32 - player_h < H_ERROR {
+            player.hspeed = conf.jump_speed;
+        }
+    }
+    let go_to = glm::length(&(position.cast::<f64>() - closest_planet.position.cast()));
     if go_to >= height {
         player_position = position;
     }
     else if matches!(player.state, Landed(_) | Anchored(_)) {
         // Stick to the ground
-        player_position = glm::normalize(&position) * height;
+        player_position = (glm::normalize(&position.cast::<f64>()) * height).cast();
     }
     player.position = player_position + up * player.height;
-}
\ No newline at end of file
+}
+
+/// Height of the terrain mesh under a position, as it is drawn, falling back
+/// to the height function where the terrain isn't generated yet
+fn ground_height(
+    planet: &planet::Planet,
+    planet_node: &SceneNode,
+    lod_view: &planet::LodView,
+    position: &glm::Vec3
+) -> f64 {
+    match planet.get_mesh_height(planet_node, lod_view, position) {
+        Some((height, _normal)) => height,
+        None => planet.get_height_f64(&position.cast()),
+    }
+}
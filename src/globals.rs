@@ -24,7 +24,14 @@ pub const H_ERROR: f32 = 0.001; // Margin of error for height computation
 
 /// Thresholds for level of detail
 pub const MAX_LOD: usize = 4;
-pub const MAX_IN_FLIGHT: u64 = 4;
 //const THRESHOLD: [f32; MAX_LOD] = [128.0, 32.0, 16.0, 8.0, 4.0, 2.0];
+pub const TERRAIN_WORKER_COUNT: usize = 4; // Threads generating terrain patches
 pub const SUBDIVS_PER_LEVEL: usize = 16; // 256: 480+380=860ms, 128: 127+98=225ms
 pub const N_LAYERS: usize = 5; // Must match with scene.frag:22
+pub const N_BIOMES: usize = 13; // Must match with scene.frag N_BIOMES
+pub const CONTINENT_SAMPLES: usize = 4096; // Points sampled to place coastlines
+pub const MAX_CRATER_CLASSES: u32 = 8; // Crater radius halves for each class
+pub const NORMAL_EPSILON: f64 = 1e-5; // Step in radians for terrain normals
+pub const SKIRT_MIN_DEPTH: f64 = 0.01; // Shallowest patch skirt, of max height
+pub const GEOMORPH_RANGE: f32 = 2.0; // Parent error, in pixel errors, where morphing ends
+pub const EXPORT_WELD_DISTANCE: f32 = 1e-6; // Joins patch edges in exported planets, in model units
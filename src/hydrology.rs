@@ -0,0 +1,246 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::cubesphere::CubeMap;
+
+//-----------------------------------------------------------------------------/
+// Hydrology
+//-----------------------------------------------------------------------------/
+// Depressions are filled by priority flood (Barnes et al., "Priority-flood: An
+// optimal depression-filling and watershed-labeling algorithm"), starting from
+// the ocean. The flood visits every texel from the lowest one it can drain to,
+// so that texel is also its flow direction, and visiting the texels in
+// reverse order accumulates the drainage area downstream.
+
+/// Parameters for rivers and lakes
+#[derive(Debug, Copy, Clone)]
+pub struct HydrologyParams {
+    pub enabled: bool,
+    pub resolution: usize,    // Texels along each side of a cube face
+    pub river_threshold: f32, // Drainage area in texels where rivers start
+    pub river_depth: f32,     // Depth of the largest rivers, of max height
+    pub lake_min_depth: f32,  // Shallower depressions are left dry, of max height
+}
+
+impl Default for HydrologyParams {
+    fn default() -> Self {
+        HydrologyParams {
+            enabled: false,
+            resolution: 128,
+            river_threshold: 150.0,
+            river_depth: 0.1,
+            lake_min_depth: 0.01,
+        }
+    }
+}
+
+/// Baked rivers and lakes
+#[derive(Debug, Clone)]
+pub struct Hydrology {
+    pub delta: CubeMap, // Change in height, carved rivers and flat lakes
+    pub river: CubeMap, // River mask, from 0 at the source to 1
+    pub lake: CubeMap,  // Lake mask, 1 in lakes
+}
+
+/// Texel in the flood queue, lowest height first
+struct Flood(f32, usize);
+
+impl PartialEq for Flood {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Flood {}
+impl PartialOrd for Flood {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Flood {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed for a min-heap, index breaks ties for determinism
+        other.0.total_cmp(&self.0).then(other.1.cmp(&self.1))
+    }
+}
+
+impl Hydrology {
+    /// Find rivers and lakes on a height map. Without an ocean, water drains
+    /// to the lowest point of the map.
+    pub fn new(
+        heights: &CubeMap,
+        params: &HydrologyParams,
+        max_height: f32,
+        has_ocean: bool,
+    ) -> Self {
+        let n = heights.data.len();
+        let canonical = (0..n).filter(|&idx| heights.canonical(idx) == idx);
+        // Slope added to filled depressions, so they still drain
+        let epsilon = max_height * 1e-5;
+
+        let mut filled = heights.data.clone();
+        let mut receiver = vec![usize::MAX; n];
+        let mut closed = vec![false; n];
+        let mut order = Vec::with_capacity(n);
+        let mut queue = BinaryHeap::new();
+
+        let mut sinks = canonical
+            .clone()
+            .filter(|&idx| has_ocean && heights.data[idx] < 0.0)
+            .collect::<Vec<_>>();
+        if sinks.is_empty() {
+            let lowest = canonical
+                .clone()
+                .min_by(|&a, &b| heights.data[a].total_cmp(&heights.data[b]));
+            sinks.extend(lowest);
+        }
+        for idx in sinks {
+            closed[idx] = true;
+            queue.push(Flood(filled[idx], idx));
+        }
+        while let Some(Flood(level, idx)) = queue.pop() {
+            order.push(idx);
+            for nb in heights.neighbours(idx) {
+                if closed[nb] {
+                    continue;
+                }
+                closed[nb] = true;
+                filled[nb] = filled[nb].max(level + epsilon);
+                receiver[nb] = idx;
+                queue.push(Flood(filled[nb], nb));
+            }
+        }
+
+        // Drainage area, each texel passing its water on downstream
+        let mut area = vec![1.0f32; n];
+        for &idx in order.iter().rev() {
+            if receiver[idx] != usize::MAX {
+                area[receiver[idx]] += area[idx];
+            }
+        }
+
+        let mut delta = CubeMap::new(heights.res);
+        let mut river = CubeMap::new(heights.res);
+        let mut lake = CubeMap::new(heights.res);
+        for idx in canonical.clone() {
+            let depth = filled[idx] - heights.data[idx];
+            if receiver[idx] != usize::MAX && depth > params.lake_min_depth * max_height {
+                // Flat lake surface at the level it overflows
+                lake.data[idx] = 1.0;
+                delta.data[idx] = depth;
+            }
+        }
+        for idx in canonical.clone() {
+            if receiver[idx] == usize::MAX || lake.data[idx] > 0.0 {
+                continue;
+            }
+            if area[idx] > params.river_threshold {
+                // Rivers grow from nothing at the source, and widen their
+                // valley into neighbouring texels
+                let strength = 1.0 - params.river_threshold / area[idx];
+                river.data[idx] = river.data[idx].max(strength);
+                for nb in heights.neighbours(idx) {
+                    if lake.data[nb] == 0.0 && receiver[nb] != usize::MAX {
+                        river.data[nb] = river.data[nb].max(strength * 0.5);
+                    }
+                }
+            }
+        }
+        for idx in canonical {
+            delta.data[idx] -= river.data[idx] * params.river_depth * max_height;
+        }
+
+        // Only canonical texels were set, copy to the rest
+        for map in [&mut delta, &mut river, &mut lake] {
+            for idx in 0..n {
+                map.data[idx] = map.data[heights.canonical(idx)];
+            }
+        }
+        Hydrology { delta, river, lake }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hills and basins, with some ocean
+    fn terrain() -> CubeMap {
+        CubeMap::from_fn(16, |dir| {
+            0.3 + 0.5 * (dir.x * 7.0).sin() * (dir.y * 5.0).cos() + 0.3 * (dir.z * 11.0).sin()
+        })
+    }
+
+    fn params() -> HydrologyParams {
+        HydrologyParams {
+            enabled: true,
+            river_threshold: 20.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn filled_lakes_leave_no_sinks() {
+        // Every depression becomes a lake and rivers are not carved, so
+        // the surface is the flooded height map
+        let heights = terrain();
+        let params = HydrologyParams {
+            river_depth: 0.0,
+            lake_min_depth: 0.0,
+            ..params()
+        };
+        let hydrology = Hydrology::new(&heights, &params, 1.0, true);
+        let surface = |idx: usize| heights.data[idx] + hydrology.delta.data[idx];
+
+        let mut lakes = 0;
+        for idx in (0..heights.data.len()).filter(|&idx| heights.canonical(idx) == idx) {
+            if heights.data[idx] < 0.0 {
+                continue;
+            }
+            lakes += (hydrology.lake.data[idx] > 0.0) as usize;
+            // Above the ocean every texel drains to a lower neighbour
+            let lowest = heights
+                .neighbours(idx)
+                .map(surface)
+                .into_iter()
+                .fold(f32::MAX, f32::min);
+            assert!(lowest < surface(idx) + 1e-6, "sink at texel {}", idx);
+        }
+        assert!(lakes > 0, "no depressions were filled");
+    }
+
+    #[test]
+    fn rivers_and_lakes_are_consistent() {
+        let heights = terrain();
+        let hydrology = Hydrology::new(&heights, &params(), 1.0, true);
+        let (mut rivers, mut lakes) = (0, 0);
+        for idx in 0..heights.data.len() {
+            let (river, lake, delta) = (
+                hydrology.river.data[idx],
+                hydrology.lake.data[idx],
+                hydrology.delta.data[idx],
+            );
+            assert!((0.0..=1.0).contains(&river));
+            assert!(lake == 0.0 || lake == 1.0);
+            // Rivers stop at lakes, and neither is in the ocean
+            assert!(river == 0.0 || lake == 0.0);
+            if heights.data[idx] < 0.0 {
+                assert!(river == 0.0 && lake == 0.0 && delta == 0.0);
+            }
+            // Lakes are filled, rivers carved
+            assert!(lake == 0.0 || delta > 0.0);
+            assert!(river == 0.0 || delta < 0.0);
+            // Edge texels and their copies agree
+            let c = heights.canonical(idx);
+            assert_eq!(river, hydrology.river.data[c]);
+            assert_eq!(lake, hydrology.lake.data[c]);
+            rivers += (river > 0.0) as usize;
+            lakes += lake as usize;
+        }
+        assert!(
+            rivers > 0 && lakes > 0,
+            "{} rivers, {} lakes",
+            rivers,
+            lakes
+        );
+    }
+}
@@ -4,9 +4,17 @@ use std::thread;
 #[allow(unused_imports)]
 use std::{mem, os::raw::c_void, ptr};
 
+mod biome;
+mod cubesphere;
+mod disk_cache;
+mod erosion;
+mod export;
 mod gamelogic;
 mod globals;
+mod hydrology;
 mod mesh;
+mod patch_address;
+mod patch_cache;
 mod player;
 mod procedural_planet;
 mod scene;
@@ -14,6 +22,7 @@ mod scene_graph;
 mod shader;
 mod texture;
 mod util;
+mod worker_pool;
 
 use glutin::event::{
     DeviceEvent,
@@ -28,6 +37,15 @@ use glutin::event_loop::ControlFlow;
 use crate::globals::{SCREEN_H, SCREEN_W};
 
 fn main() {
+    //-------------------------------------------------------------------------/
+    // Export a planet instead of starting the game, without a window
+    //-------------------------------------------------------------------------/
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--export") {
+        export::run(&args[1..]);
+        return;
+    }
+
     //-------------------------------------------------------------------------/
     // Set up the necessary objects to deal with windows and event handling
     //-------------------------------------------------------------------------/
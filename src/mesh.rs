@@ -1,5 +1,10 @@
+use crate::cubesphere;
 use crate::globals::FRACTAL_ITERATIONS;
 use crate::util;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
 use tobj;
 
 // internal helper
@@ -21,13 +26,113 @@ fn generate_color_vec(color: glm::TVec4<f32>, num: usize) -> Vec<f32> {
 // GL util VAO object
 #[derive(Copy, Clone, Default, Debug)]
 pub struct VAOobj {
-    pub vao: u32,   // Vertex Array Object
-    pub vbo: u32,   // Vertex Buffer Object
-    pub ibo: u32,   // Index Buffer Object
-    pub cbo: u32,   // Color Buffer Object
-    pub nbo: u32,   // Normal Buffer Object
-    pub texbo: u32, // Texture Buffer Object
-    pub n: i32,     // Index Count
+    pub vao: u32,             // Vertex Array Object
+    pub vbo: u32,             // Vertex Buffer Object, all attributes interleaved
+    pub ibo: u32,             // Index Buffer Object
+    pub n: i32,               // Index Count
+    pub size: u64,            // Bytes in the buffers owned by this object
+    pub layout: BufferLayout, // How the buffers were made
+}
+
+impl VAOobj {
+    /// Delete the buffers and vertex array. Shared index buffers are kept.
+    pub unsafe fn delete(&self) {
+        gl::DeleteBuffers(1, &self.vbo);
+        if self.layout.shared_indices.is_none() {
+            gl::DeleteBuffers(1, &self.ibo);
+        }
+        gl::DeleteVertexArrays(1, &self.vao);
+        util::MEMORY_USAGE.fetch_sub(self.size, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Upload a mesh with the same attributes to the buffers again, for
+    /// meshes that change like text
+    pub unsafe fn update(&mut self, mesh: &Mesh) {
+        let (vertices, _) = mesh.interleave(self.layout.quantized);
+        gl::BindVertexArray(self.vao);
+        gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+        buffer_data(gl::ARRAY_BUFFER, &vertices);
+        let mut size = vertices.len() as u64;
+        if self.layout.shared_indices.is_none() {
+            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.ibo);
+            buffer_data(gl::ELEMENT_ARRAY_BUFFER, &mesh.indices);
+            size += util::byte_size_of_array(&mesh.indices) as u64;
+        }
+        util::MEMORY_USAGE.fetch_sub(self.size, std::sync::atomic::Ordering::Relaxed);
+        util::MEMORY_USAGE.fetch_add(size, std::sync::atomic::Ordering::Relaxed);
+        self.size = size;
+        self.n = mesh.index_count;
+    }
+}
+
+/// How `Mesh::mkvao_with` lays out the buffers
+#[derive(Copy, Clone, Default, Debug)]
+pub struct BufferLayout {
+    pub quantized: bool, // Colors, normals and texture coordinates in 4 bytes each
+    pub shared_indices: Option<IndexKey>, // One index buffer for all meshes with the key
+}
+
+/// Subdivisions and skirts of a grid, all grids with the same have the same
+/// indices
+pub type IndexKey = (usize, bool);
+
+/// Index buffers shared between meshes with the same topology, like terrain
+/// patches of one resolution, and their index count. They live as long as
+/// the program.
+static SHARED_INDEX_BUFFERS: Mutex<BTreeMap<IndexKey, (u32, usize)>> = Mutex::new(BTreeMap::new());
+
+/// Where an attribute sits in an interleaved vertex
+struct Attribute {
+    index: u32, // Location in the shader, in the order of `Mesh::mkvao_with`
+    size: i32,  // Components
+    kind: gl::types::GLenum,
+    normalized: bool, // Integers read as floats in [0, 1] or [-1, 1]
+    integer: bool,    // Read as integers
+    offset: usize,
+}
+
+impl Attribute {
+    fn bytes(&self) -> usize {
+        match self.kind {
+            gl::FLOAT | gl::UNSIGNED_INT => 4 * self.size as usize,
+            gl::HALF_FLOAT => 2 * self.size as usize,
+            _ => 4, // Bytes and packed formats, 4 per vertex
+        }
+    }
+}
+
+/// Upload a whole array to the bound buffer
+unsafe fn buffer_data<T>(target: gl::types::GLenum, data: &[T]) {
+    gl::BufferData(
+        target,
+        util::byte_size_of_array(data),
+        data.as_ptr() as *const _,
+        gl::STATIC_DRAW,
+    );
+}
+
+/// Bits of an IEEE half float, rounded to nearest. Values out of range
+/// become infinite, tiny ones zero.
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    if exponent >= 31 {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        return sign;
+    }
+    // Rounding may carry into the exponent, which is still correct
+    let half = ((exponent as u32) << 10) | ((bits >> 13) & 0x3ff);
+    sign | (half + ((bits >> 12) & 1)).min(0x7c00) as u16
+}
+
+/// Unit vector and a sign in the 2_10_10_10 signed normalized format
+fn pack_2_10_10_10(v: [f32; 3], w: f32) -> u32 {
+    let component = |c: f32| ((c.clamp(-1.0, 1.0) * 511.0).round() as i32 as u32) & 0x3ff;
+    let w = (w.clamp(-1.0, 1.0).round() as i32 as u32) & 0x3;
+    component(v[0]) | component(v[1]) << 10 | component(v[2]) << 20 | w << 30
 }
 
 //-----------------------------------------------------------------------------/
@@ -39,33 +144,111 @@ pub struct Mesh {
     pub normals: Vec<f32>,
     pub texture_coordinates: Vec<f32>,
     pub colors: Vec<f32>,
+    pub biomes: Vec<u32>,         // Biome per vertex, may be empty
+    pub parent_heights: Vec<f32>, // Parent level's surface above each vertex, may be empty
+    pub tangents: Vec<f32>,       // Tangent and bitangent handedness per vertex, may be empty
     pub indices: Vec<u32>,
     pub index_count: i32,
+    pub error: f32, // Largest distance from the surface it approximates
 }
 
 impl Mesh {
+    /// A mesh from a model loaded without `single_index`, where positions,
+    /// normals and texture coordinates have their own indices. Corners with
+    /// the same three become one vertex. Missing normals are smoothed over
+    /// the vertices made from each position, so seams in the texture
+    /// coordinates don't show, while positions the model repeats stay hard
+    /// edges.
     #[allow(unused)]
     pub fn from(mesh: tobj::Mesh, color: glm::TVec4<f32>) -> Self {
-        let num_verts = mesh.positions.len() / 3;
-        let index_count = mesh.indices.len() as i32;
-        Mesh {
-            vertices: mesh.positions,
-            normals: mesh.normals,
-            texture_coordinates: if mesh.texcoords.len() > 0 {
-                mesh.texcoords
+        // Loaded with `single_index`, everything uses the position indices
+        let own_indices = |indices: &Vec<u32>| {
+            if indices.is_empty() {
+                mesh.indices.clone()
+            } else {
+                indices.clone()
+            }
+        };
+        let (texcoord_indices, normal_indices) = (
+            own_indices(&mesh.texcoord_indices),
+            own_indices(&mesh.normal_indices),
+        );
+        let mut corners: HashMap<[u32; 3], u32> = HashMap::new();
+        let mut sources: Vec<[u32; 3]> = vec![];
+        let indices = (0..mesh.indices.len())
+            .map(|k| {
+                let source = [mesh.indices[k], texcoord_indices[k], normal_indices[k]];
+                *corners.entry(source).or_insert_with(|| {
+                    sources.push(source);
+                    sources.len() as u32 - 1
+                })
+            })
+            .collect::<Vec<_>>();
+        let gather = |array: &[f32], width: usize, which: usize| {
+            (sources.iter())
+                .flat_map(|source| {
+                    let start = source[which] as usize * width;
+                    array[start..start + width].iter().copied()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let num_verts = sources.len();
+        let has_texture = !mesh.texcoords.is_empty();
+        let mut model = Mesh {
+            vertices: gather(&mesh.positions, 3, 0),
+            normals: if mesh.normals.is_empty() {
+                vec![]
+            } else {
+                gather(&mesh.normals, 3, 2)
+            },
+            texture_coordinates: if has_texture {
+                gather(&mesh.texcoords, 2, 1)
             } else {
                 vec![0.0; num_verts * 2]
             },
-            indices: mesh.indices,
             colors: generate_color_vec(color, num_verts),
-            index_count,
+            index_count: indices.len() as i32,
+            indices,
+            ..Default::default()
+        };
+        if model.normals.is_empty() {
+            let positions = sources.iter().map(|source| source[0]).collect::<Vec<_>>();
+            model.compute_normals(Some(&positions));
+        }
+        if has_texture {
+            model.compute_tangents();
         }
+        model
     }
 
-    /// Extended mkvao_simple_color to associate colors to vertices
+    /// Bytes used by the mesh data
+    pub fn byte_size(&self) -> u64 {
+        (util::byte_size_of_array(&self.vertices)
+            + util::byte_size_of_array(&self.normals)
+            + util::byte_size_of_array(&self.texture_coordinates)
+            + util::byte_size_of_array(&self.colors)
+            + util::byte_size_of_array(&self.biomes)
+            + util::byte_size_of_array(&self.parent_heights)
+            + util::byte_size_of_array(&self.tangents)
+            + util::byte_size_of_array(&self.indices)) as u64
+    }
+
+    /// Create a vertex array with the default layout, full precision and its
+    /// own index buffer
     pub unsafe fn mkvao(&self) -> VAOobj {
+        self.mkvao_with(BufferLayout::default())
+    }
+
+    /// Create a vertex array, with all attributes interleaved in one vertex
+    /// buffer. Attribute locations are position, color, normal, texture
+    /// coordinates, biome, parent height and tangent. The last three are left
+    /// disabled to read as 0 if the mesh has none.
+    pub unsafe fn mkvao_with(&self, layout: BufferLayout) -> VAOobj {
+        self.debug_validate();
         let mut id = VAOobj {
             n: self.index_count,
+            layout,
             ..Default::default()
         };
 
@@ -73,98 +256,133 @@ impl Mesh {
         gl::GenVertexArrays(1, &mut id.vao);
         gl::BindVertexArray(id.vao);
 
-        /* Create and bind index buffer, add data */
-        //let mut ibo = 0;
-        gl::GenBuffers(1, &mut id.ibo);
-        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, id.ibo);
-
-        let ibuf_size = util::byte_size_of_array(&self.indices);
-        let ibuf_data = util::pointer_to_array(&self.indices);
-
-        gl::BufferData(
-            gl::ELEMENT_ARRAY_BUFFER,
-            ibuf_size,
-            ibuf_data as *const _,
-            gl::STATIC_DRAW,
-        );
-
-        // Next sections are vertex attributes
+        /* Bind index buffer, shared or with its own data */
+        if let Some(key) = layout.shared_indices {
+            let mut shared = SHARED_INDEX_BUFFERS.lock().unwrap();
+            let &mut (ibo, count) = shared.entry(key).or_insert_with(|| {
+                let mut ibo = 0;
+                gl::GenBuffers(1, &mut ibo);
+                gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ibo);
+                buffer_data(gl::ELEMENT_ARRAY_BUFFER, &self.indices);
+                util::MEMORY_USAGE.fetch_add(
+                    util::byte_size_of_array(&self.indices) as u64,
+                    std::sync::atomic::Ordering::Relaxed,
+                );
+                (ibo, self.indices.len())
+            });
+            debug_assert_eq!(count, self.indices.len(), "other indices for {:?}", key);
+            id.ibo = ibo;
+            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, id.ibo);
+        } else {
+            gl::GenBuffers(1, &mut id.ibo);
+            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, id.ibo);
+            buffer_data(gl::ELEMENT_ARRAY_BUFFER, &self.indices);
+            id.size += util::byte_size_of_array(&self.indices) as u64;
+        }
 
-        /* Create and bind vertex buffer, add data */
+        /* Create and bind vertex buffer, add interleaved data */
+        let (vertices, attributes) = self.interleave(layout.quantized);
         gl::GenBuffers(1, &mut id.vbo);
         gl::BindBuffer(gl::ARRAY_BUFFER, id.vbo);
+        buffer_data(gl::ARRAY_BUFFER, &vertices);
+        id.size += vertices.len() as u64;
+        util::MEMORY_USAGE.fetch_add(id.size, std::sync::atomic::Ordering::Relaxed);
 
-        let vbuf_size = util::byte_size_of_array(&self.vertices);
-        let vbuf_data = util::pointer_to_array(&self.vertices);
-
-        gl::BufferData(
-            gl::ARRAY_BUFFER,
-            vbuf_size,
-            vbuf_data as *const _,
-            gl::STATIC_DRAW,
-        );
-
-        let mut attrib_idx = 0;
-        /* Define attrib ptr for vertex buffer */
-        gl::EnableVertexAttribArray(attrib_idx);
-        gl::VertexAttribPointer(attrib_idx, 3, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
-
-        /* Create and bind color buffer, add data */
-        gl::GenBuffers(1, &mut id.cbo);
-        gl::BindBuffer(gl::ARRAY_BUFFER, id.cbo);
-
-        let cbuf_size = util::byte_size_of_array(&self.colors);
-        let cbuf_data = util::pointer_to_array(&self.colors);
-
-        gl::BufferData(
-            gl::ARRAY_BUFFER,
-            cbuf_size,
-            cbuf_data as *const _,
-            gl::STATIC_DRAW,
-        );
-
-        attrib_idx += 1;
-        /* Define attrib ptr for color buffer */
-        gl::EnableVertexAttribArray(attrib_idx);
-        gl::VertexAttribPointer(attrib_idx, 4, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
-
-        /* Add normals */
-        gl::GenBuffers(1, &mut id.nbo);
-        gl::BindBuffer(gl::ARRAY_BUFFER, id.nbo);
-        let nbo_size = util::byte_size_of_array(&self.normals);
-        let nbo_data = util::pointer_to_array(&self.normals);
-
-        gl::BufferData(
-            gl::ARRAY_BUFFER,
-            nbo_size,
-            nbo_data as *const _,
-            gl::STATIC_DRAW,
-        );
+        /* Define attrib ptrs into the vertex buffer */
+        let stride = attributes.last().map_or(0, |a| a.offset + a.bytes()) as i32;
+        for a in &attributes {
+            gl::EnableVertexAttribArray(a.index);
+            let offset = a.offset as *const _;
+            if a.integer {
+                gl::VertexAttribIPointer(a.index, a.size, a.kind, stride, offset);
+            } else {
+                let normalized = if a.normalized { gl::TRUE } else { gl::FALSE };
+                gl::VertexAttribPointer(a.index, a.size, a.kind, normalized, stride, offset);
+            }
+        }
 
-        attrib_idx += 1;
-        /* Define attrib ptr for normals buffer */
-        gl::EnableVertexAttribArray(attrib_idx);
-        gl::VertexAttribPointer(attrib_idx, 3, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
-
-        /* Add texture coordinates */
-        gl::GenBuffers(1, &mut id.texbo);
-        gl::BindBuffer(gl::ARRAY_BUFFER, id.texbo);
-        let texbo_size = util::byte_size_of_array(&self.texture_coordinates);
-        let texbo_data = util::pointer_to_array(&self.texture_coordinates);
-
-        gl::BufferData(
-            gl::ARRAY_BUFFER,
-            texbo_size,
-            texbo_data as *const _,
-            gl::STATIC_DRAW,
-        );
+        id
+    }
 
-        attrib_idx += 1;
-        /* Define attrib ptr for normals buffer */
-        gl::EnableVertexAttribArray(attrib_idx);
-        gl::VertexAttribPointer(attrib_idx, 2, gl::FLOAT, gl::FALSE, 0, std::ptr::null());
+    /// Vertex data with the attributes of each vertex next to each other,
+    /// and where they sit. Missing data reads as 0.
+    fn interleave(&self, quantized: bool) -> (Vec<u8>, Vec<Attribute>) {
+        let mut attributes: Vec<Attribute> = vec![];
+        let mut add = |index, size, kind, normalized, integer| {
+            let offset = attributes
+                .last()
+                .map_or(0, |a: &Attribute| a.offset + a.bytes());
+            attributes.push(Attribute {
+                index,
+                size,
+                kind,
+                normalized,
+                integer,
+                offset,
+            });
+        };
+        add(0, 3, gl::FLOAT, false, false);
+        if quantized {
+            add(1, 4, gl::UNSIGNED_BYTE, true, false);
+            add(2, 4, gl::INT_2_10_10_10_REV, true, false);
+            add(3, 2, gl::HALF_FLOAT, false, false);
+        } else {
+            add(1, 4, gl::FLOAT, false, false);
+            add(2, 3, gl::FLOAT, false, false);
+            add(3, 2, gl::FLOAT, false, false);
+        }
+        // Integer inputs have no defined value without an array, so biomes
+        // are always there, none by default
+        add(4, 1, gl::UNSIGNED_INT, false, true);
+        if !self.parent_heights.is_empty() {
+            add(5, 1, gl::FLOAT, false, false);
+        }
+        if !self.tangents.is_empty() {
+            if quantized {
+                add(6, 4, gl::INT_2_10_10_10_REV, true, false);
+            } else {
+                add(6, 4, gl::FLOAT, false, false);
+            }
+        }
+        let stride = attributes.last().map_or(0, |a| a.offset + a.bytes());
 
-        id
+        let count = self.vertices.len() / 3;
+        let mut data = Vec::with_capacity(count * stride);
+        let get = |array: &[f32], i: usize| array.get(i).copied().unwrap_or(0.0);
+        for v in 0..count {
+            let floats = |data: &mut Vec<u8>, array: &[f32], n: usize| {
+                (0..n).for_each(|i| data.extend(get(array, v * n + i).to_le_bytes()))
+            };
+            floats(&mut data, &self.vertices, 3);
+            if quantized {
+                (0..4).for_each(|i| {
+                    data.push((get(&self.colors, v * 4 + i).clamp(0.0, 1.0) * 255.0).round() as u8)
+                });
+                let normal = [0, 1, 2].map(|i| get(&self.normals, v * 3 + i));
+                data.extend(pack_2_10_10_10(normal, 0.0).to_le_bytes());
+                (0..2).for_each(|i| {
+                    data.extend(f16_bits(get(&self.texture_coordinates, v * 2 + i)).to_le_bytes())
+                });
+            } else {
+                floats(&mut data, &self.colors, 4);
+                floats(&mut data, &self.normals, 3);
+                floats(&mut data, &self.texture_coordinates, 2);
+            }
+            data.extend(self.biomes.get(v).copied().unwrap_or(0).to_le_bytes());
+            if !self.parent_heights.is_empty() {
+                floats(&mut data, &self.parent_heights, 1);
+            }
+            if !self.tangents.is_empty() {
+                if quantized {
+                    let tangent = [0, 1, 2].map(|i| get(&self.tangents, v * 4 + i));
+                    let w = get(&self.tangents, v * 4 + 3);
+                    data.extend(pack_2_10_10_10(tangent, w).to_le_bytes());
+                } else {
+                    floats(&mut data, &self.tangents, 4);
+                }
+            }
+        }
+        (data, attributes)
     }
 
     pub fn cube(
@@ -277,6 +495,7 @@ impl Mesh {
             texture_coordinates: util::from_array_of_vec2(texture_coordinates),
             colors: generate_color_vec(color, vertex_count),
             index_count: 36,
+            ..Default::default()
         }
     }
 
@@ -325,6 +544,7 @@ impl Mesh {
             colors: generate_color_vec(glm::vec4(1.0, 1.0, 1.0, 1.0), vertex_count),
             indices,
             index_count,
+            ..Default::default()
         }
     }
 
@@ -340,10 +560,6 @@ impl Mesh {
         let vertex_count = res * res;
         let index_count = 6 * (res - 1) * (res - 1);
         let step = scale / subdivisions as f32 * 2.0;
-        util::MEMORY_USAGE.fetch_add(
-            vertex_count as u64 * 4 * 8 + index_count as u64 * 4,
-            std::sync::atomic::Ordering::Relaxed,
-        );
         // let timer = std::time::SystemTime::now();
         // eprint!("Constructing CS plane with {} vertices . . . ", vertex_count);
         let mut vertices = vec![glm::vec3(0.0, 0.0, 0.0); vertex_count];
@@ -363,24 +579,12 @@ impl Mesh {
                 );
                 // Convert to side of cubesphere
                 if cubesphere {
-                    pos = glm::vec3(
-                        pos.x
-                            * (1.0 - pos.y.powi(2) / 2.0 - pos.z.powi(2) / 2.0
-                                + pos.y.powi(2) * pos.z.powi(2) / 3.0)
-                                .sqrt(),
-                        pos.y
-                            * (1.0 - pos.x.powi(2) / 2.0 - pos.z.powi(2) / 2.0
-                                + pos.x.powi(2) * pos.z.powi(2) / 3.0)
-                                .sqrt(),
-                        pos.z
-                            * (1.0 - pos.x.powi(2) / 2.0 - pos.y.powi(2) / 2.0
-                                + pos.x.powi(2) * pos.y.powi(2) / 3.0)
-                                .sqrt(),
-                    ) * 0.5; // removed: .component_mul(&scale)
+                    pos = cubesphere::cs_point(&pos.cast(), &rotation).cast() * 0.5;
+                } else {
+                    pos = glm::rotate_x_vec3(&pos, rotation.x);
+                    pos = glm::rotate_y_vec3(&pos, rotation.y);
+                    pos = glm::rotate_z_vec3(&pos, rotation.z);
                 }
-                pos = glm::rotate_x_vec3(&pos, rotation.x);
-                pos = glm::rotate_y_vec3(&pos, rotation.y);
-                pos = glm::rotate_z_vec3(&pos, rotation.z);
                 vertices[z * res + x] = pos;
 
                 texture[z * res + x] = glm::vec2(
@@ -414,6 +618,812 @@ impl Mesh {
             ),
             indices,
             index_count: index_count as i32,
+            ..Default::default()
+        }
+    }
+
+    /// Hang a skirt from a row of vertices, a strip of triangles down to
+    /// copies of them moved by `down`. Hides cracks where the edge of a
+    /// patch meets a neighbour with a different resolution.
+    pub fn add_skirt(&mut self, edge: &[usize], down: &[glm::Vec3]) {
+        let vertices = util::to_array_of_vec3(self.vertices.clone());
+        let centre = vertices.iter().sum::<glm::Vec3>() / vertices.len() as f32;
+        let first = vertices.len();
+        // The copies take over all attributes of the vertices they hang from
+        for (&i, d) in edge.iter().zip(down) {
+            self.vertices.extend((vertices[i] + d).iter());
+            self.normals.extend_from_within(i * 3..i * 3 + 3);
+            self.texture_coordinates
+                .extend_from_within(i * 2..i * 2 + 2);
+            self.colors.extend_from_within(i * 4..i * 4 + 4);
+            if !self.biomes.is_empty() {
+                self.biomes.push(self.biomes[i]);
+            }
+            // Moves with the vertex it hangs from when morphing
+            if !self.parent_heights.is_empty() {
+                self.parent_heights.push(self.parent_heights[i]);
+            }
+            if !self.tangents.is_empty() {
+                self.tangents.extend_from_within(i * 4..i * 4 + 4);
+            }
+        }
+        for k in 0..edge.len().saturating_sub(1) {
+            let (a, b) = (edge[k], edge[k + 1]);
+            let (a_down, b_down) = (first + k, first + k + 1);
+            // Face away from the patch, where the skirt is seen from
+            let normal = glm::cross(&(vertices[b] - vertices[a]), &down[k]);
+            let quad = if glm::dot(&normal, &(vertices[a] - centre)) > 0.0 {
+                [a, b, b_down, a, b_down, a_down]
+            } else {
+                [b, a, a_down, b, a_down, b_down]
+            };
+            self.indices.extend(quad.iter().map(|&i| i as u32));
+        }
+        self.index_count = self.indices.len() as i32;
+    }
+
+    /// Smooth normals, each the sum of the normals of the triangles around
+    /// the vertex weighted by their area. Vertices with the same entry in
+    /// `sources`, copies of one vertex in the source model, share their
+    /// normal. All others keep their own, even at the same position, so hard
+    /// edges stay hard.
+    pub fn compute_normals(&mut self, sources: Option<&[u32]>) {
+        let vertices = util::to_array_of_vec3(self.vertices.clone());
+        // Cross products are as long as twice the area of their triangles
+        let mut normals = vec![glm::Vec3::zeros(); vertices.len()];
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|k| triangle[k] as usize);
+            let normal = glm::cross(&(vertices[b] - vertices[a]), &(vertices[c] - vertices[a]));
+            for i in [a, b, c] {
+                normals[i] += normal;
+            }
+        }
+        if let Some(sources) = sources {
+            let mut shared: HashMap<u32, glm::Vec3> = HashMap::new();
+            for (source, normal) in sources.iter().zip(&normals) {
+                *shared.entry(*source).or_insert_with(glm::Vec3::zeros) += normal;
+            }
+            normals = sources.iter().map(|source| shared[source]).collect();
+        }
+        let normals = normals
+            .into_iter()
+            .map(|normal| {
+                if normal.norm() > 0.0 {
+                    normal.normalize()
+                } else {
+                    normal
+                }
+            })
+            .collect();
+        self.normals = util::from_array_of_vec3(normals);
+    }
+
+    /// Tangents for normal mapping, along the first texture coordinate, with
+    /// the handedness of the bitangent in w. Like MikkTSpace, triangles add
+    /// their tangents weighted by their angle at the vertex, and the sum is
+    /// made orthogonal to the vertex normal. Smooth normals are computed
+    /// first if there are none.
+    pub fn compute_tangents(&mut self) {
+        if self.normals.len() != self.vertices.len() {
+            self.compute_normals(None);
+        }
+        let vertices = util::to_array_of_vec3(self.vertices.clone());
+        let normals = util::to_array_of_vec3(self.normals.clone());
+        let uvs = util::to_array_of_vec2(self.texture_coordinates.clone());
+        let mut tangents = vec![glm::Vec3::zeros(); vertices.len()];
+        let mut bitangents = vec![glm::Vec3::zeros(); vertices.len()];
+        let angle = |i: usize, j: usize, k: usize| {
+            let (e1, e2) = (vertices[j] - vertices[i], vertices[k] - vertices[i]);
+            if e1.norm() > 0.0 && e2.norm() > 0.0 {
+                glm::angle(&e1, &e2)
+            } else {
+                0.0
+            }
+        };
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|k| triangle[k] as usize);
+            if [a, b, c].iter().any(|&i| i >= uvs.len()) {
+                continue;
+            }
+            let (e1, e2) = (vertices[b] - vertices[a], vertices[c] - vertices[a]);
+            let (d1, d2) = (uvs[b] - uvs[a], uvs[c] - uvs[a]);
+            let det = d1.x * d2.y - d2.x * d1.y;
+            // Texture coordinates don't span the triangle, nothing to follow
+            if det.abs() < f32::EPSILON {
+                continue;
+            }
+            let tangent = (e1 * d2.y - e2 * d1.y) / det;
+            let bitangent = (e2 * d1.x - e1 * d2.x) / det;
+            for (i, j, k) in [(a, b, c), (b, c, a), (c, a, b)] {
+                let weight = angle(i, j, k);
+                tangents[i] += tangent * weight;
+                bitangents[i] += bitangent * weight;
+            }
+        }
+        let tangents = (0..vertices.len())
+            .map(|i| {
+                let normal = normals[i];
+                // Gram-Schmidt, falling back to any direction along the surface
+                let tangent = tangents[i] - normal * glm::dot(&normal, &tangents[i]);
+                let tangent = if tangent.norm() > f32::EPSILON {
+                    tangent.normalize()
+                } else if normal.norm() > 0.0 {
+                    cubesphere::tangent_basis(&normal.normalize()).0
+                } else {
+                    glm::vec3(1.0, 0.0, 0.0)
+                };
+                let bitangent = glm::cross(&normal, &tangent);
+                let w = if glm::dot(&bitangent, &bitangents[i]) < 0.0 {
+                    -1.0
+                } else {
+                    1.0
+                };
+                glm::vec4(tangent.x, tangent.y, tangent.z, w)
+            })
+            .collect();
+        self.tangents = util::from_array_of_vec4(tangents);
+    }
+}
+
+//-----------------------------------------------------------------------------/
+// Mesh editing
+//-----------------------------------------------------------------------------/
+// Operations on meshes already built, on the CPU. Used to batch meshes into a
+// single draw call, and to clean up meshes before they are uploaded.
+
+impl Mesh {
+    /// Vertices in the mesh
+    pub fn vertex_count(&self) -> usize {
+        self.vertices.len() / 3
+    }
+
+    /// Append another mesh, its indices moved past the vertices already
+    /// here. Attributes only one of the meshes has are filled with defaults
+    /// for the other.
+    pub fn merge(&mut self, other: &Mesh) {
+        let (count, other_count) = (self.vertex_count(), other.vertex_count());
+        let offset = count as u32;
+        let white = [1.0, 1.0, 1.0, 1.0];
+        let tangent = [1.0, 0.0, 0.0, 1.0];
+        append(
+            &mut self.normals,
+            &other.normals,
+            count,
+            other_count,
+            &[0.0; 3],
+        );
+        append(
+            &mut self.texture_coordinates,
+            &other.texture_coordinates,
+            count,
+            other_count,
+            &[0.0; 2],
+        );
+        append(&mut self.colors, &other.colors, count, other_count, &white);
+        append(&mut self.biomes, &other.biomes, count, other_count, &[0]);
+        append(
+            &mut self.parent_heights,
+            &other.parent_heights,
+            count,
+            other_count,
+            &[0.0],
+        );
+        append(
+            &mut self.tangents,
+            &other.tangents,
+            count,
+            other_count,
+            &tangent,
+        );
+        self.vertices
+            .extend_from_slice(&other.vertices[..other_count * 3]);
+        self.indices
+            .extend(other.indices.iter().map(|i| i + offset));
+        self.error = self.error.max(other.error);
+        self.recompute_index_count();
+    }
+
+    /// Transform positions by a matrix, and normals and tangents with them.
+    /// A mirroring matrix also flips the winding, so faces stay in front.
+    pub fn transform(&mut self, matrix: &glm::Mat4) {
+        let linear = glm::mat4_to_mat3(matrix);
+        let normal_matrix = glm::transpose(&glm::inverse(&linear));
+        let mirrored = glm::determinant(&linear) < 0.0;
+        for v in self.vertices.chunks_exact_mut(3) {
+            let p = matrix * glm::vec4(v[0], v[1], v[2], 1.0);
+            v.copy_from_slice((p.xyz() / p.w).as_slice());
+        }
+        let direction = |v: &mut [f32], m: &glm::Mat3| {
+            let d = m * glm::vec3(v[0], v[1], v[2]);
+            let d = if d.norm() > 0.0 { d.normalize() } else { d };
+            v[..3].copy_from_slice(d.as_slice());
+        };
+        for n in self.normals.chunks_exact_mut(3) {
+            direction(n, &normal_matrix);
+        }
+        for t in self.tangents.chunks_exact_mut(4) {
+            direction(t, &linear);
+            if mirrored {
+                t[3] = -t[3];
+            }
+        }
+        if mirrored {
+            self.flip_winding();
+        }
+    }
+
+    /// Merge vertices closer than `epsilon` in position and every other
+    /// attribute, so welding doesn't change how the mesh looks. Triangles
+    /// left with less than three corners are removed. Returns how many
+    /// vertices were removed.
+    pub fn weld_vertices(&mut self, epsilon: f32) -> usize {
+        let count = self.vertex_count();
+        let cell_size = epsilon.max(f32::MIN_POSITIVE);
+        let cell =
+            |i: usize| [0, 1, 2].map(|k| (self.vertices[i * 3 + k] / cell_size).floor() as i64);
+        let close = |array: &[f32], width: usize, a: usize, b: usize| {
+            array.len() < count * width
+                || (0..width)
+                    .all(|k| (array[a * width + k] - array[b * width + k]).abs() <= epsilon)
+        };
+        let same = |a: usize, b: usize| {
+            close(&self.vertices, 3, a, b)
+                && close(&self.normals, 3, a, b)
+                && close(&self.texture_coordinates, 2, a, b)
+                && close(&self.colors, 4, a, b)
+                && close(&self.parent_heights, 1, a, b)
+                && close(&self.tangents, 4, a, b)
+                && (self.biomes.len() < count || self.biomes[a] == self.biomes[b])
+        };
+
+        // Kept vertices by grid cell, a vertex is compared to those in the
+        // cells around it
+        let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+        let mut remap = vec![0u32; count];
+        let mut kept = vec![];
+        for (i, slot) in remap.iter_mut().enumerate() {
+            let [x, y, z] = cell(i);
+            let mut found = None;
+            'search: for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let Some(vertices) = grid.get(&[x + dx, y + dy, z + dz]) else {
+                            continue;
+                        };
+                        if let Some(&k) = vertices.iter().find(|&&k| same(kept[k], i)) {
+                            found = Some(k);
+                            break 'search;
+                        }
+                    }
+                }
+            }
+            *slot = match found {
+                Some(k) => k as u32,
+                None => {
+                    grid.entry([x, y, z]).or_default().push(kept.len());
+                    kept.push(i);
+                    kept.len() as u32 - 1
+                }
+            };
+        }
+
+        self.keep_vertices(&kept);
+        self.indices = self
+            .indices
+            .chunks_exact(3)
+            .map(|t| [0, 1, 2].map(|k| remap[t[k] as usize]))
+            .filter(|[a, b, c]| a != b && b != c && c != a)
+            .flatten()
+            .collect();
+        self.recompute_index_count();
+        count - kept.len()
+    }
+
+    /// Keep only the given vertices, in that order, with their attributes.
+    /// Indices are left to the caller to remap.
+    fn keep_vertices(&mut self, kept: &[usize]) {
+        let count = self.vertex_count();
+        let select = |array: &[f32], width: usize| -> Vec<f32> {
+            if array.len() < count * width {
+                return array.to_vec();
+            }
+            kept.iter()
+                .flat_map(|&i| array[i * width..(i + 1) * width].iter().copied())
+                .collect()
+        };
+        self.vertices = select(&self.vertices, 3);
+        self.normals = select(&self.normals, 3);
+        self.texture_coordinates = select(&self.texture_coordinates, 2);
+        self.colors = select(&self.colors, 4);
+        self.parent_heights = select(&self.parent_heights, 1);
+        self.tangents = select(&self.tangents, 4);
+        if self.biomes.len() >= count {
+            self.biomes = kept.iter().map(|&i| self.biomes[i]).collect();
+        }
+    }
+
+    /// Turn every triangle around, front faces become back faces
+    pub fn flip_winding(&mut self) {
+        for triangle in self.indices.chunks_exact_mut(3) {
+            triangle.swap(1, 2);
+        }
+    }
+
+    /// Draw all indices, after they were edited
+    pub fn recompute_index_count(&mut self) {
+        self.index_count = self.indices.len() as i32;
+    }
+
+    /// Smallest and largest corner of the box around the vertices, None
+    /// without vertices
+    pub fn bounding_box(&self) -> Option<(glm::Vec3, glm::Vec3)> {
+        let mut vertices = self
+            .vertices
+            .chunks_exact(3)
+            .map(|v| glm::vec3(v[0], v[1], v[2]));
+        let first = vertices.next()?;
+        Some(vertices.fold((first, first), |(min, max), v| {
+            (glm::min2(&min, &v), glm::max2(&max, &v))
+        }))
+    }
+}
+
+/// Append the values of `count` vertices with `width` values each, filling
+/// in `default` where either side doesn't have the attribute. Stays empty
+/// if neither side has it.
+fn append<T: Copy>(
+    array: &mut Vec<T>,
+    other: &[T],
+    count: usize,
+    other_count: usize,
+    default: &[T],
+) {
+    let width = default.len();
+    if array.is_empty() && other.is_empty() {
+        return;
+    }
+    array.truncate(count * width);
+    while array.len() < count * width {
+        array.push(default[array.len() % width]);
+    }
+    let take = other.len().min(other_count * width) / width * width;
+    array.extend_from_slice(&other[..take]);
+    for k in take..other_count * width {
+        array.push(default[k % width]);
+    }
+}
+
+//-----------------------------------------------------------------------------/
+// Mesh simplification
+//-----------------------------------------------------------------------------/
+// Quadric error metric simplification, after Garland and Heckbert. Edges are
+// collapsed cheapest first by moving one end onto the other, so the vertices
+// left keep their attributes as they are. The cost of a collapse is the summed
+// squared distance from the kept position to the planes of all triangles
+// merged into it. Vertices on the boundary of the mesh never move, so a
+// simplified patch still meets its neighbours. Vertices split along seams are
+// boundaries too, weld them first to simplify across the seam.
+
+impl Mesh {
+    /// Collapse edges until at most `target_triangles` are left, or the next
+    /// collapse would move the surface further than `max_error`. Either
+    /// limit may be left out with 0 or infinity. Returns the largest error
+    /// of a collapse done, also kept in the mesh error.
+    pub fn simplify(&mut self, target_triangles: usize, max_error: f32) -> f32 {
+        let mut simplifier = Simplifier::new(self);
+        let error = simplifier.run(target_triangles, max_error as f64) as f32;
+
+        // Remove the vertices no triangle uses anymore
+        let triangles: Vec<[u32; 3]> = simplifier
+            .triangles
+            .iter()
+            .zip(&simplifier.alive)
+            .filter(|(_, &alive)| alive)
+            .map(|(t, _)| *t)
+            .collect();
+        let mut remap = vec![u32::MAX; self.vertex_count()];
+        let mut kept = vec![];
+        for &i in triangles.iter().flatten() {
+            if remap[i as usize] == u32::MAX {
+                remap[i as usize] = kept.len() as u32;
+                kept.push(i as usize);
+            }
+        }
+        self.keep_vertices(&kept);
+        self.indices = triangles
+            .iter()
+            .flatten()
+            .map(|&i| remap[i as usize])
+            .collect();
+        self.recompute_index_count();
+        self.error = self.error.max(error);
+        error
+    }
+}
+
+/// Sum of squared plane distances, the upper half of a symmetric 4x4 matrix
+#[derive(Debug, Copy, Clone, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    /// Squared distance to the plane through `point` with unit `normal`
+    fn plane(normal: &glm::DVec3, point: &glm::DVec3) -> Self {
+        let p = [normal.x, normal.y, normal.z, -glm::dot(normal, point)];
+        let mut q = [0.0; 10];
+        let mut k = 0;
+        for i in 0..4 {
+            for j in i..4 {
+                q[k] = p[i] * p[j];
+                k += 1;
+            }
+        }
+        Quadric(q)
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a += b;
+        }
+    }
+
+    fn error(&self, p: &glm::DVec3) -> f64 {
+        let [xx, xy, xz, xw, yy, yz, yw, zz, zw, ww] = self.0;
+        let (x, y, z) = (p.x, p.y, p.z);
+        let error = xx * x * x
+            + yy * y * y
+            + zz * z * z
+            + ww
+            + 2.0 * (xy * x * y + xz * x * z + yz * y * z + xw * x + yw * y + zw * z);
+        error.max(0.0)
+    }
+}
+
+/// Collapse candidate, cheapest first: cost bits, from, to, and the versions
+/// of both vertices when the cost was found. Costs are never negative, so
+/// their bits sort like the costs.
+type Collapse = Reverse<(u64, u32, u32, u32, u32)>;
+
+struct Simplifier {
+    positions: Vec<glm::DVec3>,
+    quadrics: Vec<Quadric>,
+    triangles: Vec<[u32; 3]>,
+    alive: Vec<bool>,                  // Triangles not collapsed
+    vertex_triangles: Vec<Vec<usize>>, // Triangles around each vertex
+    locked: Vec<bool>,                 // Vertices on a boundary
+    removed: Vec<bool>,                // Vertices collapsed into another
+    version: Vec<u32>,                 // Bumped when a vertex' quadric changes
+    live: usize,                       // Triangles alive
+    queue: BinaryHeap<Collapse>,
+}
+
+impl Simplifier {
+    fn new(mesh: &Mesh) -> Self {
+        let count = mesh.vertex_count();
+        let positions: Vec<glm::DVec3> = mesh
+            .vertices
+            .chunks_exact(3)
+            .map(|v| glm::vec3(v[0], v[1], v[2]).cast())
+            .collect();
+        let triangles: Vec<[u32; 3]> = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect();
+        let alive: Vec<bool> = triangles
+            .iter()
+            .map(|[a, b, c]| a != b && b != c && c != a)
+            .collect();
+
+        let mut quadrics = vec![Quadric::default(); count];
+        let mut vertex_triangles = vec![vec![]; count];
+        let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+        for (t, triangle) in triangles.iter().enumerate().filter(|(t, _)| alive[*t]) {
+            let [a, b, c] = triangle.map(|i| positions[i as usize]);
+            let normal = glm::cross(&(b - a), &(c - a));
+            if normal.norm() > 0.0 {
+                let plane = Quadric::plane(&normal.normalize(), &a);
+                for &i in triangle {
+                    quadrics[i as usize].add(&plane);
+                }
+            }
+            for k in 0..3 {
+                let (i, j) = (triangle[k], triangle[(k + 1) % 3]);
+                *edges.entry((i.min(j), i.max(j))).or_default() += 1;
+                vertex_triangles[i as usize].push(t);
+            }
+        }
+
+        // Edges not shared by exactly two triangles are on a boundary
+        let mut locked = vec![false; count];
+        for (&(i, j), &n) in &edges {
+            if n != 2 {
+                locked[i as usize] = true;
+                locked[j as usize] = true;
+            }
+        }
+
+        let mut simplifier = Simplifier {
+            positions,
+            quadrics,
+            live: alive.iter().filter(|&&a| a).count(),
+            triangles,
+            alive,
+            vertex_triangles,
+            locked,
+            removed: vec![false; count],
+            version: vec![0; count],
+            queue: BinaryHeap::new(),
+        };
+        for &(i, j) in edges.keys() {
+            simplifier.push(i, j);
+        }
+        simplifier
+    }
+
+    /// Queue the cheaper way to collapse an edge, if either end may move
+    fn push(&mut self, a: u32, b: u32) {
+        let cost = |from: u32, to: u32| {
+            let mut quadric = self.quadrics[from as usize];
+            quadric.add(&self.quadrics[to as usize]);
+            quadric.error(&self.positions[to as usize])
+        };
+        let best = [(a, b), (b, a)]
+            .into_iter()
+            .filter(|&(from, _)| !self.locked[from as usize])
+            .map(|(from, to)| (cost(from, to), from, to))
+            .min_by(|x, y| x.0.total_cmp(&y.0));
+        if let Some((cost, from, to)) = best {
+            let versions = (self.version[from as usize], self.version[to as usize]);
+            self.queue
+                .push(Reverse((cost.to_bits(), from, to, versions.0, versions.1)));
+        }
+    }
+
+    fn live_triangles(&self, v: u32) -> impl Iterator<Item = &[u32; 3]> {
+        self.vertex_triangles[v as usize]
+            .iter()
+            .filter(|&&t| self.alive[t])
+            .map(|&t| &self.triangles[t])
+    }
+
+    fn neighbours(&self, v: u32) -> HashSet<u32> {
+        self.live_triangles(v)
+            .flatten()
+            .copied()
+            .filter(|&i| i != v)
+            .collect()
+    }
+
+    /// A collapse must keep the surface manifold, the ends may only share
+    /// the neighbours of the triangles on the edge, and no triangle may be
+    /// turned over.
+    fn allowed(&self, from: u32, to: u32) -> bool {
+        let shared = self
+            .live_triangles(from)
+            .filter(|t| t.contains(&to))
+            .count();
+        let common = self
+            .neighbours(from)
+            .intersection(&self.neighbours(to))
+            .count();
+        if shared == 0 || common != shared {
+            return false;
+        }
+        self.live_triangles(from)
+            .filter(|t| !t.contains(&to))
+            .all(|t| {
+                let old = t.map(|i| self.positions[i as usize]);
+                let new = t.map(|i| self.positions[if i == from { to } else { i } as usize]);
+                let normal = |[a, b, c]: [glm::DVec3; 3]| glm::cross(&(b - a), &(c - a));
+                glm::dot(&normal(old), &normal(new)) > 0.0
+            })
+    }
+
+    fn collapse(&mut self, from: u32, to: u32) {
+        for t in std::mem::take(&mut self.vertex_triangles[from as usize]) {
+            if !self.alive[t] {
+                continue;
+            }
+            if self.triangles[t].contains(&to) {
+                self.alive[t] = false;
+                self.live -= 1;
+            } else {
+                for i in self.triangles[t].iter_mut().filter(|i| **i == from) {
+                    *i = to;
+                }
+                self.vertex_triangles[to as usize].push(t);
+            }
+        }
+        let alive = &self.alive;
+        self.vertex_triangles[to as usize].retain(|&t| alive[t]);
+        let quadric = self.quadrics[from as usize];
+        self.quadrics[to as usize].add(&quadric);
+        self.removed[from as usize] = true;
+        self.version[to as usize] += 1;
+        for neighbour in self.neighbours(to) {
+            self.push(to, neighbour);
+        }
+    }
+
+    /// Collapse down to the target, returning the largest error
+    fn run(&mut self, target_triangles: usize, max_error: f64) -> f64 {
+        let mut error: f64 = 0.0;
+        while self.live > target_triangles {
+            let Some(Reverse((cost, from, to, from_version, to_version))) = self.queue.pop() else {
+                break;
+            };
+            let (f, t) = (from as usize, to as usize);
+            if self.removed[f]
+                || self.removed[t]
+                || self.version[f] != from_version
+                || self.version[t] != to_version
+            {
+                continue;
+            }
+            let cost = f64::from_bits(cost).sqrt();
+            if cost > max_error {
+                break;
+            }
+            if self.allowed(from, to) {
+                self.collapse(from, to);
+                error = error.max(cost);
+            }
+        }
+        error
+    }
+}
+
+//-----------------------------------------------------------------------------/
+// Mesh validation
+//-----------------------------------------------------------------------------/
+// Checks for meshes that would draw wrong or read past their buffers, run on
+// every mesh before it is uploaded in debug builds. Attribute arrays may be
+// empty, the vertex array then reads them as 0, otherwise they need a value
+// for each vertex.
+
+/// Problems found in a mesh, all empty for a valid mesh
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeshReport {
+    pub degenerate_triangles: Vec<usize>, // Triangles with a repeated corner or no area
+    pub non_finite: Vec<(&'static str, usize)>, // Attribute and vertex with NaN or infinity
+    pub bad_indices: Vec<usize>,          // Places in the indices past the last vertex
+    pub length_mismatches: Vec<(&'static str, usize)>, // Array and its length, not matching the vertices
+    pub index_count: Option<i32>, // Index count, if it isn't the number of indices
+    pub non_manifold_edges: Vec<(u32, u32)>, // Edges shared by more than two triangles
+}
+
+impl MeshReport {
+    pub fn is_ok(&self) -> bool {
+        *self == MeshReport::default()
+    }
+}
+
+impl fmt::Display for MeshReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "no problems");
+        }
+        let mut problems = vec![];
+        let mut list = |name: &str, count: usize, first: String| {
+            if count > 0 {
+                problems.push(format!("{} {} (first {})", count, name, first));
+            }
+        };
+        list(
+            "degenerate triangles",
+            self.degenerate_triangles.len(),
+            format!("{:?}", self.degenerate_triangles.first()),
+        );
+        list(
+            "non-finite values",
+            self.non_finite.len(),
+            format!("{:?}", self.non_finite.first()),
+        );
+        list(
+            "indices out of range",
+            self.bad_indices.len(),
+            format!("{:?}", self.bad_indices.first()),
+        );
+        list(
+            "array lengths not matching",
+            self.length_mismatches.len(),
+            format!("{:?}", self.length_mismatches.first()),
+        );
+        list(
+            "non-manifold edges",
+            self.non_manifold_edges.len(),
+            format!("{:?}", self.non_manifold_edges.first()),
+        );
+        if let Some(count) = self.index_count {
+            problems.push(format!("index count {} not matching the indices", count));
+        }
+        write!(f, "{}", problems.join(", "))
+    }
+}
+
+impl Mesh {
+    /// Check the mesh for broken geometry and inconsistent arrays
+    pub fn validate(&self) -> MeshReport {
+        let mut report = MeshReport::default();
+        let count = self.vertex_count();
+
+        let arrays: [(&'static str, &[f32], usize); 6] = [
+            ("vertices", &self.vertices, 3),
+            ("normals", &self.normals, 3),
+            ("texture_coordinates", &self.texture_coordinates, 2),
+            ("colors", &self.colors, 4),
+            ("parent_heights", &self.parent_heights, 1),
+            ("tangents", &self.tangents, 4),
+        ];
+        for (name, array, width) in arrays {
+            if array.len() != count * width && (name == "vertices" || !array.is_empty()) {
+                report.length_mismatches.push((name, array.len()));
+            }
+            for (i, values) in array.chunks(width).enumerate() {
+                if values.iter().any(|v| !v.is_finite()) {
+                    report.non_finite.push((name, i));
+                }
+            }
+        }
+        if !self.biomes.is_empty() && self.biomes.len() != count {
+            report.length_mismatches.push(("biomes", self.biomes.len()));
+        }
+        if !self.indices.len().is_multiple_of(3) {
+            report
+                .length_mismatches
+                .push(("indices", self.indices.len()));
+        }
+        if self.index_count as usize != self.indices.len() {
+            report.index_count = Some(self.index_count);
+        }
+        report.bad_indices = (self.indices.iter().enumerate())
+            .filter(|(_, &i)| i as usize >= count)
+            .map(|(k, _)| k)
+            .collect();
+
+        let position = |i: u32| {
+            let v = &self.vertices[i as usize * 3..i as usize * 3 + 3];
+            glm::vec3(v[0], v[1], v[2])
+        };
+        let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+        for (t, triangle) in self.indices.chunks_exact(3).enumerate() {
+            let [a, b, c] = [triangle[0], triangle[1], triangle[2]];
+            if a == b || b == c || c == a {
+                report.degenerate_triangles.push(t);
+                continue;
+            }
+            for (i, j) in [(a, b), (b, c), (c, a)] {
+                *edges.entry((i.min(j), i.max(j))).or_default() += 1;
+            }
+            if triangle.iter().all(|&i| (i as usize) < count) {
+                let (a, b, c) = (position(a), position(b), position(c));
+                if glm::cross(&(b - a), &(c - a)).norm() == 0.0 {
+                    report.degenerate_triangles.push(t);
+                }
+            }
+        }
+        report.non_manifold_edges = (edges.into_iter())
+            .filter(|&(_, n)| n > 2)
+            .map(|(edge, _)| edge)
+            .collect();
+        report.non_manifold_edges.sort();
+        report
+    }
+
+    /// Report problems with the mesh in debug builds, and stop before
+    /// indices past the vertex buffer get drawn
+    fn debug_validate(&self) {
+        if cfg!(debug_assertions) {
+            let report = self.validate();
+            if !report.is_ok() {
+                eprintln!(
+                    "Mesh with {} vertices and {} indices: {}",
+                    self.vertex_count(),
+                    self.indices.len(),
+                    report
+                );
+            }
+            assert!(report.bad_indices.is_empty(), "Mesh indices out of range");
         }
     }
 }
@@ -450,3 +1460,327 @@ pub fn fractal_noise(
     }
     noise_sum
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unit square in the xz plane, two triangles sharing an edge but not
+    /// its vertices
+    fn split_square() -> Mesh {
+        let vertices = vec![
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 1.0, //
+            0.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 1.0,
+        ];
+        Mesh {
+            normals: [0.0, 1.0, 0.0].repeat(6),
+            colors: [1.0, 1.0, 1.0, 1.0].repeat(6),
+            texture_coordinates: vec![0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0],
+            indices: vec![0, 2, 1, 3, 5, 4],
+            index_count: 6,
+            vertices,
+            ..Default::default()
+        }
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn merge_offsets_indices_and_fills_attributes() {
+        let mut mesh = split_square();
+        let mut other = split_square();
+        other.colors.clear();
+        other.tangents = [1.0, 0.0, 0.0, -1.0].repeat(6);
+        other.error = 2.0;
+        mesh.merge(&other);
+
+        assert_eq!(mesh.vertex_count(), 12);
+        assert_eq!(&mesh.indices[6..], &[6, 8, 7, 9, 11, 10]);
+        assert_eq!(mesh.index_count, 12);
+        assert_eq!(mesh.colors, [1.0; 48].to_vec());
+        assert_eq!(mesh.tangents.len(), 48);
+        assert_close(&mesh.tangents[..4], &[1.0, 0.0, 0.0, 1.0]);
+        assert_close(&mesh.tangents[24..28], &[1.0, 0.0, 0.0, -1.0]);
+        assert!(mesh.biomes.is_empty());
+        assert_eq!(mesh.error, 2.0);
+    }
+
+    #[test]
+    fn transform_moves_vertices_and_turns_normals() {
+        let mut mesh = split_square();
+        let matrix = glm::translate(&glm::identity(), &glm::vec3(1.0, 2.0, 3.0))
+            * glm::rotate_x(&glm::identity(), std::f32::consts::FRAC_PI_2)
+            * glm::scale(&glm::identity(), &glm::vec3(2.0, 2.0, 2.0));
+        mesh.transform(&matrix);
+
+        assert_close(&mesh.vertices[3..6], &[3.0, 2.0, 3.0]);
+        assert_close(&mesh.vertices[6..9], &[3.0, 0.0, 3.0]);
+        assert_close(&mesh.normals[..3], &[0.0, 0.0, 1.0]);
+        assert_eq!(mesh.indices, vec![0, 2, 1, 3, 5, 4]);
+    }
+
+    #[test]
+    fn mirroring_transform_flips_winding() {
+        let mut mesh = split_square();
+        mesh.tangents = [1.0, 0.0, 0.0, 1.0].repeat(6);
+        mesh.transform(&glm::scale(&glm::identity(), &glm::vec3(-1.0, 1.0, 1.0)));
+
+        assert_close(&mesh.vertices[3..6], &[-1.0, 0.0, 0.0]);
+        assert_close(&mesh.normals[..3], &[0.0, 1.0, 0.0]);
+        assert_close(&mesh.tangents[..4], &[-1.0, 0.0, 0.0, -1.0]);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn weld_merges_matching_vertices() {
+        let mut mesh = split_square();
+        mesh.vertices[9] += 1e-4;
+        let removed = mesh.weld_vertices(1e-3);
+
+        assert_eq!(removed, 2);
+        assert_eq!(mesh.vertex_count(), 4);
+        assert_eq!(mesh.normals.len(), 12);
+        assert_eq!(mesh.texture_coordinates.len(), 8);
+        assert_eq!(mesh.indices, vec![0, 2, 1, 0, 3, 2]);
+        assert_eq!(mesh.index_count, 6);
+    }
+
+    #[test]
+    fn weld_keeps_seams_and_drops_degenerate_triangles() {
+        let mut mesh = split_square();
+        mesh.texture_coordinates[6] = 0.5;
+        assert_eq!(mesh.weld_vertices(1e-3), 1);
+
+        let mut mesh = split_square();
+        mesh.vertices[15..18].copy_from_slice(&[0.0, 0.0, 0.0]);
+        mesh.texture_coordinates[10..12].copy_from_slice(&[0.0, 0.0]);
+        mesh.weld_vertices(1e-3);
+        assert_eq!(mesh.indices, vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn flip_winding_reverses_triangles() {
+        let mut mesh = split_square();
+        mesh.flip_winding();
+        assert_eq!(mesh.indices, vec![0, 1, 2, 3, 4, 5]);
+        mesh.flip_winding();
+        assert_eq!(mesh.indices, split_square().indices);
+    }
+
+    #[test]
+    fn recompute_index_count_follows_indices() {
+        let mut mesh = split_square();
+        mesh.indices.truncate(3);
+        mesh.recompute_index_count();
+        assert_eq!(mesh.index_count, 3);
+    }
+
+    #[test]
+    fn bounding_box_holds_vertices() {
+        let mut mesh = split_square();
+        mesh.vertices[4] = -2.0;
+        let (min, max) = mesh.bounding_box().unwrap();
+        assert_eq!(min, glm::vec3(0.0, -2.0, 0.0));
+        assert_eq!(max, glm::vec3(1.0, 0.0, 1.0));
+        assert!(Mesh::default().bounding_box().is_none());
+    }
+
+    /// Grid of `n` by `n` quads over the unit square, raised by `height`
+    fn grid(n: usize, height: impl Fn(f32, f32) -> f32) -> Mesh {
+        let mut mesh = Mesh::default();
+        for j in 0..=n {
+            for i in 0..=n {
+                let (x, z) = (i as f32 / n as f32, j as f32 / n as f32);
+                mesh.vertices.extend([x, height(x, z), z]);
+            }
+        }
+        let at = |i: usize, j: usize| (j * (n + 1) + i) as u32;
+        for j in 0..n {
+            for i in 0..n {
+                let (a, b, c, d) = (at(i, j), at(i + 1, j), at(i + 1, j + 1), at(i, j + 1));
+                mesh.indices.extend([a, c, b, a, d, c]);
+            }
+        }
+        mesh.recompute_index_count();
+        mesh
+    }
+
+    /// Edges used by a single triangle, by the positions of their ends
+    fn boundary_edges(mesh: &Mesh) -> HashSet<[[u32; 3]; 2]> {
+        let position = |i: u32| {
+            let v = &mesh.vertices[i as usize * 3..i as usize * 3 + 3];
+            [v[0].to_bits(), v[1].to_bits(), v[2].to_bits()]
+        };
+        let mut edges: HashMap<[[u32; 3]; 2], usize> = HashMap::new();
+        for t in mesh.indices.chunks_exact(3) {
+            for k in 0..3 {
+                let mut edge = [position(t[k]), position(t[(k + 1) % 3])];
+                edge.sort();
+                *edges.entry(edge).or_default() += 1;
+            }
+        }
+        edges
+            .into_iter()
+            .filter(|(_, n)| *n == 1)
+            .map(|(e, _)| e)
+            .collect()
+    }
+
+    #[test]
+    fn simplify_meets_triangle_budget_and_keeps_boundary() {
+        let mut mesh = grid(16, |_, _| 0.0);
+        let boundary = boundary_edges(&mesh);
+        assert_eq!(boundary.len(), 64);
+
+        mesh.simplify(100, f32::INFINITY);
+        assert!(mesh.indices.len() / 3 <= 100);
+        assert_eq!(mesh.index_count as usize, mesh.indices.len());
+        assert_eq!(boundary_edges(&mesh), boundary);
+        assert!(mesh
+            .indices
+            .iter()
+            .all(|&i| (i as usize) < mesh.vertex_count()));
+
+        // Every triangle still faces up
+        for t in mesh.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|k| {
+                let v = &mesh.vertices[t[k] as usize * 3..t[k] as usize * 3 + 3];
+                glm::vec3(v[0], v[1], v[2])
+            });
+            assert!(glm::cross(&(b - a), &(c - a)).y > 0.0);
+        }
+    }
+
+    #[test]
+    fn simplify_stops_at_error_bound() {
+        let bump = |x: f32, z: f32| 0.2 * (x * std::f32::consts::PI).sin() * (z * 3.0).sin();
+        let mut mesh = grid(16, bump);
+        let boundary = boundary_edges(&mesh);
+
+        let error = mesh.simplify(0, 0.005);
+        let triangles = mesh.indices.len() / 3;
+        assert!(error <= 0.005);
+        assert_eq!(mesh.error, error);
+        assert!(triangles < 512, "nothing simplified");
+        assert!(triangles > 62, "simplified past the error bound");
+        assert_eq!(boundary_edges(&mesh), boundary);
+
+        // Vertices left are on the surface, they are never moved
+        for v in mesh.vertices.chunks_exact(3) {
+            assert!((v[1] - bump(v[0], v[2])).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn flat_quad_normals_face_up() {
+        let mut mesh = split_square();
+        mesh.normals.clear();
+        mesh.compute_normals(None);
+        assert_close(&mesh.normals, &[0.0, 1.0, 0.0].repeat(6));
+    }
+
+    #[test]
+    fn normals_are_area_weighted_and_shared_by_source() {
+        // A large triangle facing +z and a small one facing +y, meeting at
+        // the origin. The small one has its own copy of the origin.
+        let mut mesh = Mesh {
+            vertices: vec![
+                0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 2.0, 0.0, //
+                0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0,
+            ],
+            indices: vec![0, 1, 2, 3, 4, 5],
+            index_count: 6,
+            ..Default::default()
+        };
+        // Copies of the same source vertex share their normal, weighted
+        // by the areas 2 and 0.5
+        mesh.compute_normals(Some(&[0, 1, 2, 0, 4, 5]));
+        let shared = glm::normalize(&glm::vec3(0.0, 1.0, 4.0));
+        assert_close(&mesh.normals[0..3], shared.as_slice());
+        assert_close(&mesh.normals[9..12], shared.as_slice());
+        assert_close(&mesh.normals[3..6], &[0.0, 0.0, 1.0]);
+
+        // Separate source vertices keep a hard edge
+        mesh.compute_normals(None);
+        assert_close(&mesh.normals[0..3], &[0.0, 0.0, 1.0]);
+        assert_close(&mesh.normals[9..12], &[0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn tangent_handedness_follows_mirrored_uvs() {
+        let mut mesh = split_square();
+        mesh.compute_tangents();
+        let mut mirrored = split_square();
+        for uv in mirrored.texture_coordinates.chunks_exact_mut(2) {
+            uv[0] = 1.0 - uv[0];
+        }
+        mirrored.compute_tangents();
+
+        for (mesh, tangent) in [(mesh, [1.0, 0.0, 0.0]), (mirrored, [-1.0, 0.0, 0.0])] {
+            let tangents = util::to_array_of_vec4(mesh.tangents.clone());
+            let normals = util::to_array_of_vec3(mesh.normals.clone());
+            for (t, n) in tangents.iter().zip(normals) {
+                assert_close(&t.as_slice()[..3], &tangent);
+                // The bitangent points along v, +z, either way
+                let bitangent = glm::cross(&n, &t.xyz()) * t.w;
+                assert_close(bitangent.as_slice(), &[0.0, 0.0, 1.0]);
+            }
+        }
+    }
+
+    #[test]
+    fn interleave_always_has_biomes() {
+        let mut with_biomes = split_square();
+        with_biomes.biomes = vec![3; 6];
+        for (mesh, biome) in [(split_square(), 0u32), (with_biomes, 3)] {
+            for quantized in [false, true] {
+                let (data, attributes) = mesh.interleave(quantized);
+                let attribute = attributes.iter().find(|a| a.index == 4).unwrap();
+                assert!(attribute.integer && attribute.kind == gl::UNSIGNED_INT);
+                let stride = data.len() / mesh.vertex_count();
+                for vertex in data.chunks_exact(stride) {
+                    let bytes = &vertex[attribute.offset..attribute.offset + 4];
+                    assert_eq!(u32::from_le_bytes(bytes.try_into().unwrap()), biome);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn validate_reports_broken_meshes() {
+        assert!(split_square().validate().is_ok());
+        assert!(Mesh::default().validate().is_ok());
+
+        let mut mesh = split_square();
+        mesh.normals[4] = f32::NAN;
+        mesh.colors.pop();
+        mesh.indices.extend([0, 0, 9, 1, 2]);
+        let report = mesh.validate();
+        assert_eq!(report.non_finite, vec![("normals", 1)]);
+        assert_eq!(
+            report.length_mismatches,
+            vec![("colors", 23), ("indices", 11)]
+        );
+        assert_eq!(report.bad_indices, vec![8]);
+        assert_eq!(report.degenerate_triangles, vec![2]);
+        assert_eq!(report.index_count, Some(6));
+
+        // Three triangles on one edge, and two without area
+        let mut mesh = split_square();
+        mesh.vertices.extend([0.5, 1.0, 0.5, 0.5, -1.0, 0.5]);
+        mesh.indices.extend([0, 2, 6, 2, 0, 7, 1, 1, 2, 0, 1, 3]);
+        mesh.normals.clear();
+        mesh.colors.clear();
+        mesh.texture_coordinates.clear();
+        mesh.recompute_index_count();
+        let report = mesh.validate();
+        assert_eq!(report.non_manifold_edges, vec![(0, 2)]);
+        assert_eq!(report.degenerate_triangles, vec![4, 5]);
+        assert!(report.length_mismatches.is_empty());
+    }
+}
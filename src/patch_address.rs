@@ -0,0 +1,307 @@
+use crate::cubesphere;
+use nalgebra_glm as glm;
+
+//-----------------------------------------------------------------------------/
+// Patch addresses
+//-----------------------------------------------------------------------------/
+// Terrain patches form a quadtree on each cube face. A patch is addressed by
+// its face and the quadrant taken at each level down from the face, so the
+// address holds the level and the patch's place on the face. Quadrant i lies
+// towards the displacement (1 - 2 (i & 1), 1 - 2 (i >> 1)) in face
+// coordinates (a, b), the order `lod_terrain` puts children in.
+//
+// `lod_terrain` only walks down from the roots. Looking patches up by
+// direction, parents, bounds and neighbours are for queries across the
+// quadtree, so far only the tests make them.
+
+/// Side of a patch, the way to a neighbour along face coordinates
+#[cfg(test)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Side {
+    PlusA,
+    MinusA,
+    PlusB,
+    MinusB,
+}
+
+#[cfg(test)]
+impl Side {
+    pub const ALL: [Side; 4] = [Side::PlusA, Side::MinusA, Side::PlusB, Side::MinusB];
+
+    /// Step towards the side in face coordinates
+    fn step(&self) -> (f64, f64) {
+        match self {
+            Side::PlusA => (1.0, 0.0),
+            Side::MinusA => (-1.0, 0.0),
+            Side::PlusB => (0.0, 1.0),
+            Side::MinusB => (0.0, -1.0),
+        }
+    }
+}
+
+/// Where a patch covers the unit sphere
+#[cfg(test)]
+#[derive(Debug, Copy, Clone)]
+pub struct SphereBounds {
+    pub centre: glm::DVec3,       // Direction of the patch centre
+    pub corners: [glm::DVec3; 4], // Directions of the corners, in quadrant order
+    pub angle: f64,               // From the centre, in radians, holds the patch
+}
+
+#[cfg(test)]
+impl SphereBounds {
+    pub fn contains(&self, dir: &glm::DVec3) -> bool {
+        glm::dot(&self.centre, &glm::normalize(dir)) >= self.angle.cos()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PatchAddress {
+    pub face: usize,   // Index of `cubesphere::face_rotations`
+    pub path: Vec<u8>, // Quadrant from the face down to the patch
+}
+
+impl PatchAddress {
+    /// The patch covering a whole face
+    pub fn root(face: usize) -> Self {
+        PatchAddress { face, path: vec![] }
+    }
+
+    /// Patch at `level` holding the face coordinates (a, b)
+    #[cfg(test)]
+    pub fn at(face: usize, a: f64, b: f64, level: usize) -> Self {
+        let mut address = Self::root(face);
+        for _ in 0..level {
+            let quadrant = address.quadrant_towards(a, b);
+            address = address.child(quadrant);
+        }
+        address
+    }
+
+    /// Patch at `level` under a direction from the planet centre
+    #[cfg(test)]
+    pub fn from_direction(dir: &glm::DVec3, level: usize) -> Self {
+        let (face, a, b) = cubesphere::cs_inverse(dir);
+        Self::at(face, a, b, level)
+    }
+
+    pub fn level(&self) -> usize {
+        self.path.len()
+    }
+
+    #[cfg(test)]
+    pub fn parent(&self) -> Option<Self> {
+        let mut path = self.path.clone();
+        path.pop()?;
+        Some(PatchAddress {
+            face: self.face,
+            path,
+        })
+    }
+
+    pub fn child(&self, quadrant: usize) -> Self {
+        let mut path = self.path.clone();
+        path.push(quadrant as u8);
+        PatchAddress {
+            face: self.face,
+            path,
+        }
+    }
+
+    pub fn children(&self) -> [Self; 4] {
+        [0, 1, 2, 3].map(|quadrant| self.child(quadrant))
+    }
+
+    /// Quadrant of this patch holding the face coordinates (a, b)
+    pub fn quadrant_towards(&self, a: f64, b: f64) -> usize {
+        let (x, z) = self.centre();
+        (a < x) as usize + 2 * (b < z) as usize
+    }
+
+    /// Centre of the patch in face coordinates
+    pub fn centre(&self) -> (f64, f64) {
+        let (mut x, mut z, mut half) = (0.0, 0.0, 1.0);
+        for &quadrant in &self.path {
+            half /= 2.0;
+            x += if quadrant & 1 == 0 { half } else { -half };
+            z += if quadrant & 2 == 0 { half } else { -half };
+        }
+        (x, z)
+    }
+
+    /// Half the side of the patch in face coordinates
+    pub fn half_size(&self) -> f64 {
+        0.5f64.powi(self.level() as i32)
+    }
+
+    /// Rotation of the face, as taken by `Mesh::cs_plane`
+    pub fn rotation(&self) -> glm::Vec3 {
+        cubesphere::face_rotations()[self.face]
+    }
+
+    /// Position on the face plane, as taken by `Mesh::cs_plane`
+    pub fn position(&self) -> glm::Vec3 {
+        let (x, z) = self.centre();
+        glm::vec3(x as f32, 1.0, z as f32)
+    }
+
+    /// Scale on the face plane, as taken by `Mesh::cs_plane`
+    pub fn scale(&self) -> glm::Vec3 {
+        let half = self.half_size() as f32;
+        glm::vec3(half, half, half)
+    }
+
+    /// Where the patch covers the unit sphere. Edges of patches bow slightly
+    /// on the sphere, so the angle is also checked at their middles.
+    #[cfg(test)]
+    pub fn bounds(&self) -> SphereBounds {
+        let (x, z) = self.centre();
+        let half = self.half_size();
+        let rotation = self.rotation();
+        let dir = |a: f64, b: f64| {
+            glm::normalize(&cubesphere::cs_point(
+                &glm::vec3(x + a, 1.0, z + b),
+                &rotation,
+            ))
+        };
+        let centre = dir(0.0, 0.0);
+        let corners = [
+            dir(half, half),
+            dir(-half, half),
+            dir(half, -half),
+            dir(-half, -half),
+        ];
+        let edges = [
+            dir(half, 0.0),
+            dir(-half, 0.0),
+            dir(0.0, half),
+            dir(0.0, -half),
+        ];
+        let angle = corners
+            .iter()
+            .chain(&edges)
+            .map(|d| glm::dot(&centre, d).clamp(-1.0, 1.0).acos())
+            .fold(0.0, f64::max);
+        SphereBounds {
+            centre,
+            corners,
+            angle,
+        }
+    }
+
+    /// Patch of the same level sharing the edge on `side`. Across the edge of
+    /// a face the neighbour is on the adjacent face, whose coordinates may
+    /// run another way, so its side facing back isn't always the opposite.
+    #[cfg(test)]
+    pub fn neighbour(&self, side: Side) -> Self {
+        let (x, z) = self.centre();
+        let half = self.half_size();
+        let (da, db) = side.step();
+        let (a, b) = (x + 2.0 * half * da, z + 2.0 * half * db);
+        if a.abs() < 1.0 && b.abs() < 1.0 {
+            return Self::at(self.face, a, b, self.level());
+        }
+        // Middle of the shared edge on the cube, moved half a patch into the
+        // adjacent face, which lies along the step
+        let frames = cubesphere::face_frames();
+        let frame = &frames[self.face];
+        let cube = |v: glm::Vec3| v.cast::<f64>();
+        let edge =
+            cube(frame.normal) + cube(frame.u) * (x + half * da) + cube(frame.v) * (z + half * db);
+        let outwards = cube(frame.u) * da + cube(frame.v) * db;
+        let face = frames
+            .iter()
+            .position(|f| cube(f.normal) == outwards)
+            .unwrap();
+        let inside = edge - cube(frame.normal) * half;
+        let next = &frames[face];
+        Self::at(
+            face,
+            glm::dot(&inside, &cube(next.u)),
+            glm::dot(&inside, &cube(next.v)),
+            self.level(),
+        )
+    }
+
+    /// Neighbours on all sides, in the order of `Side::ALL`
+    #[cfg(test)]
+    pub fn neighbours(&self) -> [Self; 4] {
+        Side::ALL.map(|side| self.neighbour(side))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every patch of the levels up to 3, on all faces
+    fn addresses() -> Vec<PatchAddress> {
+        let mut level: Vec<PatchAddress> = (0..6).map(PatchAddress::root).collect();
+        let mut all = level.clone();
+        for _ in 0..3 {
+            level = level.iter().flat_map(|a| a.children()).collect();
+            all.extend(level.iter().cloned());
+        }
+        all
+    }
+
+    #[test]
+    fn neighbours_lead_back() {
+        for a in addresses() {
+            for side in Side::ALL {
+                let n = a.neighbour(side);
+                assert_eq!(n.level(), a.level());
+                assert_ne!(n, a);
+                assert!(
+                    n.neighbours().contains(&a),
+                    "{:?} on {:?} of {:?} doesn't lead back",
+                    n,
+                    side,
+                    a
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn centre_direction_finds_the_patch() {
+        for a in addresses() {
+            assert_eq!(
+                PatchAddress::from_direction(&a.bounds().centre, a.level()),
+                a
+            );
+        }
+    }
+
+    #[test]
+    fn bounds_hold_the_patch_and_not_its_neighbours() {
+        for a in addresses() {
+            let bounds = a.bounds();
+            // Corners of the children are the patch's corners, the middles
+            // of its edges and its centre
+            for child in a.children() {
+                for corner in child.bounds().corners {
+                    assert!(bounds.contains(&corner), "{:?} misses {:?}", a, corner);
+                }
+            }
+            for n in a.neighbours() {
+                assert!(
+                    !bounds.contains(&n.bounds().centre),
+                    "{:?} holds {:?}",
+                    a,
+                    n
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn children_have_the_patch_as_parent() {
+        for a in addresses() {
+            for i in 0..4 {
+                assert_eq!(a.child(i).parent(), Some(a.clone()));
+            }
+        }
+        assert_eq!(PatchAddress::root(0).parent(), None);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::scene_graph::{Node, SceneNode, VAOStatus};
+
+//-----------------------------------------------------------------------------/
+// Patch cache
+//-----------------------------------------------------------------------------/
+// Terrain patches stay in the quadtree after it stops displaying them, so
+// going back to them is cheap. A patch the quadtree did not reach this frame
+// is stale, along with everything below it. When the patches use more memory
+// than the budgets, stale patches are evicted, least recently used first:
+// their buffers and meshes are freed, their subtrees collapsed, and they are
+// left to be generated again.
+
+/// Memory budgets for terrain patches, and what they used at the last check
+#[derive(Debug, Copy, Clone)]
+pub struct PatchCache {
+    pub gpu_budget: u64, // Bytes of vertex and index buffers
+    pub cpu_budget: u64, // Bytes of meshes kept after upload
+    pub gpu_usage: u64,
+    pub cpu_usage: u64,
+    pub evicted: usize, // Patches evicted in total
+}
+
+/// Stale patch, evicted together with its subtree
+struct Stale {
+    node: *mut SceneNode,
+    last_used: u64,
+    gpu: u64,
+    cpu: u64,
+}
+
+impl PatchCache {
+    pub fn new(gpu_budget: u64, cpu_budget: u64) -> Self {
+        PatchCache {
+            gpu_budget,
+            cpu_budget,
+            gpu_usage: 0,
+            cpu_usage: 0,
+            evicted: 0,
+        }
+    }
+
+    /// Evict stale patches from the planets' quadtrees until they fit the
+    /// budgets. Patches reached by the quadtree in `frame` are kept.
+    pub unsafe fn evict(&mut self, planet_nodes: &[Node], frame: u64) {
+        self.gpu_usage = 0;
+        self.cpu_usage = 0;
+        let mut stale = vec![];
+        for node in planet_nodes {
+            if node.get_n_children() < 1 {
+                continue;
+            }
+            // Terrain faces are always reached, the ocean is not cached
+            let planet_root = &*node.children[0];
+            for &face in &planet_root.children {
+                self.collect(&mut *face, frame, &mut stale);
+            }
+        }
+
+        stale.sort_by_key(|s| s.last_used);
+        for s in stale {
+            if self.gpu_usage <= self.gpu_budget && self.cpu_usage <= self.cpu_budget {
+                break;
+            }
+            evict(&mut *s.node);
+            self.gpu_usage -= s.gpu;
+            self.cpu_usage -= s.cpu;
+            self.evicted += 1;
+        }
+    }
+
+    /// Add up memory used by the patches below `node`, noting stale subtrees
+    unsafe fn collect(&mut self, node: &mut SceneNode, frame: u64, stale: &mut Vec<Stale>) {
+        if node.last_used < frame {
+            let (gpu, cpu) = usage(node);
+            self.gpu_usage += gpu;
+            self.cpu_usage += cpu;
+            // A patch still generating would be made ready again
+            let status = node.vao_generate.lock().unwrap().0;
+            if gpu + cpu > 0 && !matches!(status, VAOStatus::Generating) {
+                stale.push(Stale {
+                    node,
+                    last_used: node.last_used,
+                    gpu,
+                    cpu,
+                });
+            }
+            return;
+        }
+        self.gpu_usage += node.vao.size;
+        self.cpu_usage += node.vao_generate.lock().unwrap().1.byte_size();
+        for &child in &node.children {
+            self.collect(&mut *child, frame, stale);
+        }
+    }
+}
+
+/// Memory used by a patch and all patches below it, GPU and CPU
+unsafe fn usage(node: &SceneNode) -> (u64, u64) {
+    let mut gpu = node.vao.size;
+    let mut cpu = node.vao_generate.lock().unwrap().1.byte_size();
+    for &child in &node.children {
+        let (g, c) = usage(&*child);
+        gpu += g;
+        cpu += c;
+    }
+    (gpu, cpu)
+}
+
+/// Free a patch and its subtree, leaving the patch to be generated again
+unsafe fn evict(node: &mut SceneNode) {
+    for &child in &node.children {
+        evict(&mut *child);
+        // Children are leaked boxes, see `SceneNode::add_child`
+        drop(Box::from_raw(child));
+    }
+    node.children.clear();
+    if node.index_count != -1 {
+        node.vao.delete();
+        node.vao = Default::default();
+        node.index_count = -1;
+    }
+    *node.vao_generate.lock().unwrap() = (VAOStatus::NotStarted, Default::default());
+    node.geometric_error = 0.0;
+    node.bounding_radius = 0.0;
+}
@@ -1,13 +1,136 @@
+use crate::biome::{self, Biome, BiomeParams};
+use crate::cubesphere::{self, CubeMap};
+use crate::disk_cache::{self, PatchKey};
+use crate::erosion::{self, ErosionParams, ThermalParams};
+use crate::hydrology::{Hydrology, HydrologyParams};
+use crate::patch_address::PatchAddress;
 use crate::scene_graph::{self, SceneNodeType};
+use crate::worker_pool::{self, WorkerPool};
 use crate::{mesh, shader::Shader};
 use nalgebra_glm as glm;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use crate::globals::*;
 use crate::util;
 
+/// The noise crate exports two `Perlin`s, named once here so each use isn't
+/// ambiguous
+type Perlin = noise::Perlin;
+
+/// Patches drawn from a quadtree node, the leaves that have a mesh
+unsafe fn displayed_patches(
+    node: &mut scene_graph::SceneNode,
+    patches: &mut Vec<*mut scene_graph::SceneNode>,
+) {
+    match node.node_type {
+        SceneNodeType::Planet if node.index_count != -1 => patches.push(node),
+        SceneNodeType::Empty => {
+            for &child in &node.children {
+                displayed_patches(&mut *child, patches);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Cancel terrain generation for a patch and the patches below it
+unsafe fn cancel_jobs(node: &mut scene_graph::SceneNode) {
+    if let Some(job) = node.job.take() {
+        job.cancel();
+        let mut status = node.vao_generate.lock().unwrap();
+        if let scene_graph::VAOStatus::Generating = status.0 {
+            status.0 = scene_graph::VAOStatus::NotStarted;
+        }
+    }
+    for &child in &node.children {
+        cancel_jobs(&mut *child);
+    }
+}
+
+/// Centre of a patch on the sphere, which its vertices are relative to. Same
+/// radius as `Mesh::cs_plane`.
+fn patch_origin(address: &PatchAddress) -> glm::DVec3 {
+    let position = address.position();
+    cubesphere::cs_point(
+        &glm::vec3(position.x, 1.0, position.z).cast(),
+        &address.rotation(),
+    ) * 0.5
+}
+
+/// Distance along a ray from the origin in direction `dir` to where it hits
+/// a triangle, by Möller-Trumbore
+fn ray_triangle(
+    dir: &glm::DVec3,
+    v0: &glm::DVec3,
+    v1: &glm::DVec3,
+    v2: &glm::DVec3,
+) -> Option<f64> {
+    let (e1, e2) = (v1 - v0, v2 - v0);
+    let p = glm::cross(dir, &e2);
+    let det = glm::dot(&e1, &p);
+    if det.abs() < 1e-15 {
+        return None;
+    }
+    let s = -v0;
+    let u = glm::dot(&s, &p) / det;
+    let q = glm::cross(&s, &e1);
+    let v = glm::dot(dir, &q) / det;
+    // Small tolerance so rays along shared edges hit either triangle
+    let eps = 1e-5;
+    if u < -eps || v < -eps || u + v > 1.0 + eps {
+        return None;
+    }
+    let t = glm::dot(&e2, &q) / det;
+    (t > 0.0).then_some(t)
+}
+
+/// Distance along a ray from the origin to where it hits a grid of triangles
+/// laid out like `Mesh::cs_plane`, and the triangle hit. Tries the cell
+/// under the ray and its neighbours, as the mapping to the sphere bends the
+/// cell edges slightly.
+fn ray_grid<F: Fn(i64, i64) -> glm::DVec3>(
+    dir: &glm::DVec3,
+    vertex: F,
+    (cx, cz): (i64, i64),
+    subdivisions: usize,
+) -> Option<(f64, [glm::DVec3; 3])> {
+    let last = subdivisions as i64 - 1;
+    for gz in (cz - 1).max(0)..=(cz + 1).min(last) {
+        for gx in (cx - 1).max(0)..=(cx + 1).min(last) {
+            let triangles = [
+                [vertex(gx + 1, gz), vertex(gx, gz), vertex(gx + 1, gz + 1)],
+                [vertex(gx, gz), vertex(gx, gz + 1), vertex(gx + 1, gz + 1)],
+            ];
+            for [v0, v1, v2] in triangles {
+                if let Some(t) = ray_triangle(dir, &v0, &v1, &v2) {
+                    return Some((t, [v0, v1, v2]));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Hermite interpolation between 0 and 1 for `x` in `[edge0, edge1]`
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
 pub static PLANET_COUNTER: AtomicU64 = AtomicU64::new(0);
-pub static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+/// Workers generating terrain patches for all planets
+static TERRAIN_WORKERS: OnceLock<WorkerPool> = OnceLock::new();
+/// Set once a patch couldn't be written to the disk cache
+static CACHE_STORE_FAILED: AtomicBool = AtomicBool::new(false);
+/// Patches of one resolution have the same indices, and small vertices
+fn patch_buffers(subdivisions: usize, skirts: bool) -> mesh::BufferLayout {
+    mesh::BufferLayout {
+        quantized: true,
+        shared_indices: Some((subdivisions, skirts)),
+    }
+}
 
 /// Procedurally generated planet. Will use a quad-tree form, each side
 /// either drawing a plane or subdividing into nodes covering recursively
@@ -53,7 +176,7 @@ pub static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
 ///         |---right
 ///         |---top
 ///         +---bottom
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Clone)]
 pub struct Planet {
     pub node: usize, // scene node kept separate
     pub planet_id: usize,
@@ -87,11 +210,91 @@ pub struct Planet {
     pub ocean_dark_color: glm::TVec3<f32>,
     pub ocean_light_color: glm::TVec3<f32>,
 
-    pub noise_fn: u32,
+    pub noise_fn: NoiseFunction,
     pub seed: u32,
     // Some independent generators for increased variation
     pub noise: NoiseParams,
-    perlin: noise::Perlin,
+    pub ridged: RidgedParams,
+    pub worley: WorleyParams,
+    pub warp: WarpParams,
+    pub continents: ContinentParams,
+    pub craters: CraterParams,
+    pub erosion: ErosionParams,
+    pub thermal: ThermalParams,
+    pub biomes: BiomeParams,
+    pub hydrology: HydrologyParams,
+    pub cache_dir: Option<PathBuf>, // Of the disk cache, patches aren't cached without
+    perlin: Perlin,
+    continent_perlin: Perlin,
+    moisture_perlin: Perlin,
+    continent_level: f64, // Mask value at the coastline, from land fraction
+    // Baked eroded heights, sampled in place of the noise
+    erosion_map: Option<Arc<CubeMap>>,
+    hydrology_map: Option<Arc<Hydrology>>, // Rivers and lakes, on top of erosion
+    ocean_distance: Option<Arc<CubeMap>>,  // For biomes, in radians
+    params_hash: u64,                      // `terrain_hash` when baked, keys the disk cache
+}
+
+/// Terrain patches drawn and culled in a frame
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CullCount {
+    pub drawn: usize,
+    pub frustum: usize, // Outside the view frustum
+    pub horizon: usize, // Hidden behind the planet's horizon
+}
+
+/// Camera that the terrain level of detail is chosen for
+#[derive(Debug, Copy, Clone)]
+pub struct LodView {
+    pub position: glm::Vec3,
+    pub pixel_scale: f32, // Pixels covered by one unit at unit distance
+    pub pixel_error: f32, // Subdivide patches that are off by more pixels
+    pub frame: u64,       // Patches reached are marked with it for the cache
+}
+
+impl LodView {
+    pub fn new(
+        position: glm::Vec3,
+        fov: f32,
+        window_height: f32,
+        pixel_error: f32,
+        frame: u64,
+    ) -> Self {
+        LodView {
+            position,
+            pixel_scale: window_height / (2.0 * (fov / 2.0).tan()),
+            pixel_error,
+            frame,
+        }
+    }
+
+    /// Send what the vertex shader needs to morph patches with the same
+    /// metric that subdivides them. A patch has its parent's shape where the
+    /// parent's error covers the pixel error, and its own where it covers
+    /// `GEOMORPH_RANGE` times as many pixels.
+    pub unsafe fn update_uniforms(&self, sh: &Shader) {
+        gl::Uniform1f(
+            sh.get_uniform_location("u_lod_pixel_scale"),
+            self.pixel_scale,
+        );
+        gl::Uniform1f(
+            sh.get_uniform_location("u_lod_pixel_error"),
+            self.pixel_error,
+        );
+        gl::Uniform1f(sh.get_uniform_location("u_lod_morph_range"), GEOMORPH_RANGE);
+    }
+
+    /// How much of its parent's shape a patch vertex at a world position
+    /// takes, as `morph_factor` in the vertex shader
+    pub fn morph_factor(&self, morph_error: f32, vertex: &glm::Vec3) -> f32 {
+        if morph_error <= 0.0 {
+            return 0.0;
+        }
+        let distance = glm::length(&(vertex - self.position));
+        let pixels = morph_error / distance.max(1e-6) * self.pixel_scale;
+        let range = self.pixel_error * (GEOMORPH_RANGE - 1.0);
+        ((self.pixel_error * GEOMORPH_RANGE - pixels) / range).clamp(0.0, 1.0)
+    }
 }
 
 use noise::*;
@@ -116,14 +319,79 @@ impl Planet {
             ocean_lvl: 0.0,
             ocean_dark_color: glm::vec3(0.01, 0.2, 0.3),
             ocean_light_color: glm::vec3(0.04, 0.3, 0.43),
-            noise_fn: 0,
-            perlin: noise::Perlin::new().set_seed(seed),
+            noise_fn: NoiseFunction::Fbm,
+            perlin: Perlin::new().set_seed(seed),
+            continent_perlin: Perlin::new().set_seed(seed.wrapping_add(1)),
+            moisture_perlin: Perlin::new().set_seed(seed.wrapping_add(2)),
             seed,
             //noise_size  : 10.0,
             ..Default::default()
         }
     }
 
+    /// Precompute terrain data depending on the planet parameters. Must be
+    /// called after the parameters are set, before generating any terrain.
+    pub fn bake_terrain(&mut self) {
+        // Baked maps and cached patches hold the projection
+        cubesphere::fix_projection();
+        self.params_hash = self.terrain_hash();
+        if self.continents.enabled {
+            // Coastline at the mask value leaving the requested fraction of
+            // the surface above ocean level, found by bisection
+            let samples = util::fibonacci_sphere(CONTINENT_SAMPLES)
+                .iter()
+                .map(|p| p.cast())
+                .map(|p| (self.continent_mask(&p), self.detail(&p)))
+                .collect::<Vec<_>>();
+            let land = self.continents.land_fraction.clamp(0.0, 1.0);
+            let (mut lo, mut hi) = (-2.0, 2.0);
+            for _ in 0..32 {
+                let level = (lo + hi) / 2.0;
+                let above = samples
+                    .iter()
+                    .filter(|&&(mask, detail)| self.continent_height(mask - level, detail) > 0.0)
+                    .count();
+                if (above as f64) / (samples.len() as f64) > land as f64 {
+                    lo = level;
+                } else {
+                    hi = level;
+                }
+            }
+            self.continent_level = (lo + hi) / 2.0;
+        }
+        self.erosion_map = None;
+        if self.erosion.enabled || self.thermal.enabled {
+            // Erode the height map, the terrain is then sampled from it.
+            // Thermal erosion shares the resolution of the hydraulic erosion.
+            let mut eroded =
+                CubeMap::from_fn(self.erosion.resolution, |p| self.noise(&p.cast()) as f32);
+            if self.erosion.enabled {
+                erosion::hydraulic(&mut eroded, &self.erosion, self.seed);
+            }
+            if self.thermal.enabled {
+                erosion::thermal(&mut eroded, &self.thermal);
+            }
+            self.erosion_map = Some(Arc::new(eroded));
+        }
+        self.hydrology_map = None;
+        if self.hydrology.enabled {
+            let heights =
+                CubeMap::from_fn(self.hydrology.resolution, |p| self.noise(&p.cast()) as f32);
+            self.hydrology_map = Some(Arc::new(Hydrology::new(
+                &heights,
+                &self.hydrology,
+                self.max_height,
+                self.has_ocean,
+            )));
+        }
+        self.ocean_distance = None;
+        if self.biomes.enabled && self.has_ocean {
+            let heights =
+                CubeMap::from_fn(self.biomes.resolution, |p| self.noise(&p.cast()) as f32);
+            self.ocean_distance = Some(Arc::new(biome::ocean_distance(&heights)));
+        }
+    }
+
     /// Update uniforms for planet in shader
     pub unsafe fn update_uniforms(&self, sh: &Shader) {
         gl::Uniform1ui(
@@ -185,7 +453,17 @@ impl Planet {
             sh.get_uniform_location(&format!("u_planets[{}].color_blending", self.planet_id)),
             self.color_blending,
         ); // u_planets[id].color_blending
-           //-Ocean---------------------------------------------------------------/
+        for i in 0..N_BIOMES {
+            gl::Uniform3fv(
+                sh.get_uniform_location(&format!(
+                    "u_planets[{}].biome_colors[{}]",
+                    self.planet_id, i
+                )),
+                1,
+                self.biomes.colors[i].as_ptr(),
+            ); // u_planets[id].biome_colors[0..N_BIOMES]
+        }
+        //-Ocean---------------------------------------------------------------/
         gl::Uniform3fv(
             sh.get_uniform_location(&format!("u_planets[{}].ocean_dark_color", self.planet_id)),
             1,
@@ -198,23 +476,12 @@ impl Planet {
         ); // u_planets[id].ocean_light_color
     }
     /// Set level of detail to be drawn, generate new if needed
-    pub unsafe fn lod(
-        &mut self,
-        node: &mut scene_graph::SceneNode,
-        player_position: glm::TVec3<f32>,
-    ) {
+    pub unsafe fn lod(&mut self, node: &mut scene_graph::SceneNode, view: &LodView) {
         self.parts = 0;
         self.position = glm::vec4_to_vec3(
             &(node.current_transformation_matrix * glm::vec4(0.0, 0.0, 0.0, 1.0)),
         );
-        let rotations: [glm::TVec3<f32>; 6] = [
-            glm::vec3(0.0, 0.0, 0.0),                          // Top
-            glm::vec3(std::f32::consts::PI, 0.0, 0.0),         // Bottom
-            glm::vec3(std::f32::consts::FRAC_PI_2, 0.0, 0.0),  // Front
-            glm::vec3(-std::f32::consts::FRAC_PI_2, 0.0, 0.0), // Back
-            glm::vec3(0.0, 0.0, -std::f32::consts::FRAC_PI_2), // Left
-            glm::vec3(0.0, 0.0, std::f32::consts::FRAC_PI_2),  // Right
-        ];
+        let rotations = cubesphere::face_rotations();
         // Handle top of tree and call lod_terrain for terrain sides
         // let mut planet_root;
         if node.get_n_children() < 1 {
@@ -229,14 +496,9 @@ impl Planet {
         let planet_root = node.get_child(0);
 
         for (i, &child) in (&planet_root.children).iter().enumerate() {
-            self.lod_terrain(
-                &mut *child,
-                glm::vec3(1.0, 1.0, 1.0),
-                rotations[i],
-                glm::vec3(0.0, 1.0, 0.0), //positions[i],
-                0,
-                player_position,
-            );
+            self.lod_terrain(&mut *child, &PatchAddress::root(i), view);
+            // Face nodes sit directly in the planet root
+            (*child).position = (*child).model_offset;
         }
 
         if !self.has_ocean {
@@ -247,15 +509,17 @@ impl Planet {
             let mut ocean_root = scene_graph::SceneNode::with_type(SceneNodeType::Empty);
             for i in 0..6 {
                 // Generate sides if they don't exist yet
+                let subdivisions = 32;
                 let ocean_mesh = mesh::Mesh::cs_plane(
                     glm::vec3(1.0, 1.0, 1.0),
                     rotations[i],
                     glm::vec3(0.0, 1.0, 0.0), //positions[i],
-                    32,
+                    subdivisions,
                     None,
                     true,
                 );
-                let mut ocean_node = scene_graph::SceneNode::from_vao(ocean_mesh.mkvao());
+                let vao = ocean_mesh.mkvao_with(patch_buffers(subdivisions, false));
+                let mut ocean_node = scene_graph::SceneNode::from_vao(vao);
                 ocean_node.node_type = SceneNodeType::Ocean;
                 ocean_node.planet_id = self.planet_id;
                 ocean_root.add_child(&ocean_node);
@@ -267,39 +531,27 @@ impl Planet {
     pub unsafe fn lod_terrain(
         &self,
         node: &mut scene_graph::SceneNode, // Either gets the mesh (leaf) or becomes a parent to four subdivisions
-        scale: glm::TVec3<f32>,            // 2D scale. Modify x and z components
-        rotation: glm::TVec3<f32>,         // Won't be modified, same for all subdivs of a side
-        position: glm::TVec3<f32>,         // 2D position. Modify x and z components
-        level: usize,
-        player_position: glm::TVec3<f32>,
+        address: &PatchAddress,            // Which patch the node holds
+        view: &LodView,
     ) -> bool {
-        let displacements: [glm::TVec3<f32>; 4] = [
-            glm::vec3(1.0, 0.0, 1.0),
-            glm::vec3(-1.0, 0.0, 1.0),
-            glm::vec3(1.0, 0.0, -1.0),
-            glm::vec3(-1.0, 0.0, -1.0),
-        ];
+        let level = address.level();
 
-        let planet_center = self.position;
-        let center_position = planet_center
-            + glm::rotate_z_vec3(
-                &glm::rotate_x_vec3(&(position * self.radius), rotation.x),
-                rotation.z,
-            );
-        let plane_normal = glm::normalize(&(center_position - planet_center));
-        let player_normal = glm::normalize(&(player_position - planet_center));
-
-        // cos of angle between player position and plane center
-        let dot = glm::dot(&plane_normal, &glm::normalize(&player_normal));
-        let angle = dot.acos();
-        let angle_lim = (std::f32::consts::FRAC_PI_2 * 1.6) / ((level as f32) + 1.0).powf(1.4);
+        // The parent places the node
+        node.model_offset = patch_origin(address).cast();
+        node.last_used = view.frame;
 
-        // Use height to limit LoD when planet is further away
-        let player_height = glm::length(&(player_position - self.position));
-        let height_lim = self.radius * (1.0 + self.max_height)
-            + (self.radius * 2.6) / ((level as f32) + 1.0).powf(1.5);
+        // Subdivide when the error of this patch's mesh, seen from the
+        // closest point of its bounds, covers too many pixels. A model unit
+        // is the planet's diameter. Patches are generated before they can
+        // subdivide, as their error is only known from the mesh.
+        let to_world = self.radius * 2.0;
+        let centre = self.position + node.model_offset * to_world;
+        let distance = (glm::length(&(view.position - centre)) - node.bounding_radius * to_world)
+            .max(f32::EPSILON);
+        let pixels = node.geometric_error * to_world / distance * view.pixel_scale;
+        let generated = node.index_count != -1 || node.get_n_children() > 0;
 
-        if angle < angle_lim && player_height < height_lim && level < self.max_lod {
+        if generated && pixels > view.pixel_error && level < self.max_lod {
             // Generate next level
             if node.children.len() == 0 {
                 for i in 0..4 {
@@ -309,23 +561,24 @@ impl Planet {
             }
             node.node_type = SceneNodeType::Empty;
             let mut ready = true;
+            let model_offset = node.model_offset;
+            let morph_error = node.geometric_error * to_world;
             for i in 0..4 {
-                ready &= self.lod_terrain(
-                    &mut node.get_child(i),
-                    scale / 2.0,
-                    rotation,
-                    position + (displacements[i] * scale.x) / 2.0,
-                    level + 1,
-                    player_position,
-                );
+                ready &= self.lod_terrain(&mut node.get_child(i), &address.child(i), view);
+                let child = node.get_child(i);
+                child.position = child.model_offset - model_offset;
+                child.morph_error = morph_error;
             }
             if !ready {
                 node.node_type = SceneNodeType::Planet;
             }
             return true;
         }
-        // Use this detail level
+        // Use this detail level, finer patches below aren't needed any more
         node.node_type = SceneNodeType::Planet;
+        for &child in &node.children {
+            cancel_jobs(&mut *child);
+        }
         if node.index_count != -1 {
             return true;
         }
@@ -339,138 +592,851 @@ impl Planet {
 
         return match status {
             NotStarted => {
-                // Start thread generating terrain
-                let in_flight = IN_FLIGHT.load(Ordering::Relaxed);
-                if in_flight >= MAX_IN_FLIGHT {
-                    return false;
-                }
-                IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
-                let planet = *self;
+                // Queue terrain generation, closest patches first
+                let planet = self.clone();
                 *arc_vao_status.lock().unwrap() = (Generating, mesh::Mesh::default());
-                std::thread::spawn(move || {
-                    let mut planet_mesh = mesh::Mesh::cs_plane(
-                        scale,
-                        rotation,
-                        position,
-                        (1 + level) * SUBDIVS_PER_LEVEL,
-                        None,
-                        true,
-                    );
-                    planet.displace_vertices(&mut planet_mesh);
-                    *arc_vao_status.lock().unwrap() = (Ready, planet_mesh);
-                    IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+                let priority = worker_pool::Priority { distance, level };
+                let workers = TERRAIN_WORKERS.get_or_init(|| WorkerPool::new(TERRAIN_WORKER_COUNT));
+                let key = self.patch_key(address);
+                let address = address.clone();
+                let job = workers.submit(priority, move |job| {
+                    let cached = key.as_ref().and_then(disk_cache::load);
+                    let planet_mesh = cached.unwrap_or_else(|| {
+                        let planet_mesh = planet.patch_mesh(&address, true);
+                        let stored = key.map(|key| disk_cache::store(&key, &planet_mesh));
+                        if let Some(Err(e)) = stored {
+                            // Likely the same for every patch, once is enough
+                            if !CACHE_STORE_FAILED.swap(true, Ordering::Relaxed) {
+                                eprintln!("Could not cache terrain patches: {}", e);
+                            }
+                        }
+                        planet_mesh
+                    });
+                    let mut status = arc_vao_status.lock().unwrap();
+                    if !job.is_cancelled() {
+                        *status = (Ready, planet_mesh);
+                    }
                 });
+                node.job = Some(job);
                 false
             }
             Ready => {
                 // Finish creating scene node
-                let vao = arc_vao_status.lock().unwrap().1.mkvao();
+                node.job = None;
+                let status = arc_vao_status.lock().unwrap();
+                let subdivisions = (1 + level) * SUBDIVS_PER_LEVEL;
+                let vao = status.1.mkvao_with(patch_buffers(subdivisions, true));
                 node.update_vao(vao);
+                node.geometric_error = status.1.error;
+                let vertices = util::to_array_of_vec3(status.1.vertices.clone());
+                node.bounding_radius = vertices.iter().fold(0.0, |r, v| r.max(glm::length(v)));
+                // Skirts come after the grid and reach below the surface
+                let res = subdivisions + 1;
+                node.inner_radius = vertices[..res * res].iter().fold(f32::MAX, |r, v| {
+                    r.min(glm::length(&(v + node.model_offset)))
+                });
                 true
             }
             Generating => {
-                // Just return while thread is still working
+                // Just return while thread is still working, closer jobs go first
+                if let Some(job) = &node.job {
+                    job.set_distance(distance);
+                    if job.has_failed() {
+                        eprintln!("Could not generate terrain patch {:?}", address);
+                        arc_vao_status.lock().unwrap().0 = Failed;
+                    }
+                }
                 false
             }
+            // Coarser patches stay in its place
+            Failed => false,
         };
     }
 
-    pub fn get_height(&self, pos: &glm::TVec3<f32>) -> f32 {
-        let pos = glm::vec3(
-            (100.0 * pos.x).round() / 100.0,
-            (100.0 * pos.y).round() / 100.0,
-            (100.0 * pos.z).round() / 100.0,
+    /// Skip drawing displayed patches outside the frustum, given by its side
+    /// planes, or behind the horizon seen from `camera`. Uses the
+    /// transformations from this frame, and node types from `lod`.
+    pub unsafe fn cull(
+        &self,
+        node: &mut scene_graph::SceneNode,
+        frustum: &[glm::Vec4; 4],
+        camera: &glm::Vec3,
+        count: &mut CullCount,
+    ) {
+        if node.get_n_children() < 1 || node.node_type == SceneNodeType::PlanetSkip {
+            return;
+        }
+        let mut patches = vec![];
+        for &face in &(*node.children[0]).children {
+            displayed_patches(&mut *face, &mut patches);
+        }
+        // The drawn surface hides what is behind it. Triangles sag below
+        // their vertices by up to the patch error.
+        let to_world = self.radius * 2.0;
+        let occluder = patches.iter().fold(f32::MAX, |r, patch| {
+            r.min((**patch).inner_radius - (**patch).geometric_error)
+        }) * to_world;
+        let camera_distance = glm::length(&(camera - self.position));
+        let camera_horizon = (camera_distance.powi(2) - occluder.powi(2)).max(0.0).sqrt();
+
+        for patch in patches {
+            let patch = &mut *patch;
+            let centre = glm::vec4_to_vec3(
+                &(patch.current_transformation_matrix * glm::vec4(0.0, 0.0, 0.0, 1.0)),
+            );
+            let radius = patch.bounding_radius * to_world;
+            if frustum
+                .iter()
+                .any(|plane| glm::dot(&plane.xyz(), &centre) + plane.w < -radius)
+            {
+                patch.node_type = SceneNodeType::PlanetSkip;
+                count.frustum += 1;
+                continue;
+            }
+            // Seen over the horizon, the farthest visible point at a height
+            // is as far beyond the horizon as the horizon is from that height
+            if camera_distance > occluder {
+                let top = glm::length(&(centre - self.position)) + radius;
+                let patch_horizon = (top.powi(2) - occluder.powi(2)).max(0.0).sqrt();
+                if glm::length(&(camera - centre)) - radius > camera_horizon + patch_horizon {
+                    patch.node_type = SceneNodeType::PlanetSkip;
+                    count.horizon += 1;
+                    continue;
+                }
+            }
+            count.drawn += 1;
+        }
+    }
+
+    /// Hash of everything that shapes the terrain meshes, besides the seed
+    pub fn terrain_hash(&self) -> u64 {
+        let params = format!(
+            "{:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+            self.noise_fn,
+            self.noise,
+            self.ridged,
+            self.worley,
+            self.warp,
+            self.continents,
+            self.craters,
+            self.erosion,
+            self.thermal,
+            self.biomes,
+            self.hydrology,
+            self.max_height,
+            self.has_ocean,
+            SUBDIVS_PER_LEVEL,
+            SKIRT_MIN_DEPTH,
+            NORMAL_EPSILON,
+            cubesphere::projection(),
         );
-        let position = glm::vec3(
-            (100.0 * self.position.x).round() / 100.0,
-            (100.0 * self.position.y).round() / 100.0,
-            (100.0 * self.position.z).round() / 100.0,
+        disk_cache::hash(params.as_bytes())
+    }
+
+    /// Disk cache key of a patch, None if patches aren't cached
+    fn patch_key(&self, address: &PatchAddress) -> Option<PatchKey> {
+        Some(PatchKey {
+            directory: self.cache_dir.clone()?,
+            seed: self.seed,
+            params: self.params_hash,
+            address: address.clone(),
+        })
+    }
+
+    /// Seed and parameter hash the disk cache keeps the planet's patches by,
+    /// once baked
+    pub fn cache_id(&self) -> (u32, u64) {
+        (self.seed, self.params_hash)
+    }
+
+    pub fn get_height(&self, pos: &glm::TVec3<f32>) -> f32 {
+        self.get_height_f64(&pos.cast()) as f32
+    }
+
+    /// Distance from the planet centre to the terrain below a position, in
+    /// double precision all the way from the position to the noise
+    pub fn get_height_f64(&self, pos: &glm::DVec3) -> f64 {
+        let dir = glm::normalize(&(pos - self.position.cast()));
+        self.radius as f64 * (1.0 + self.noise(&dir))
+    }
+
+    /// Height from the planet centre and normal of the terrain mesh under a
+    /// position, for the level of detail currently displayed and morphed as
+    /// seen from `view`, so it matches what is drawn. `node` is the planet's
+    /// scene node. None where no terrain is displayed yet.
+    pub fn get_mesh_height(
+        &self,
+        node: &scene_graph::SceneNode,
+        view: &LodView,
+        pos: &glm::TVec3<f32>,
+    ) -> Option<(f64, glm::TVec3<f32>)> {
+        let model: glm::DMat4 = node.current_transformation_matrix.cast();
+        let local = glm::inverse(&model) * glm::vec4(pos.x as f64, pos.y as f64, pos.z as f64, 1.0);
+        let dir = glm::normalize(&local.xyz());
+
+        // Follow the quadtree down to the displayed patch
+        let (face, a, b) = cubesphere::cs_inverse(&dir);
+        if node.get_n_children() < 1 {
+            return None;
+        }
+        let planet_root = unsafe { &*node.children[0] };
+        let mut patch = unsafe { &*planet_root.children[face] };
+        let mut address = PatchAddress::root(face);
+        while patch.node_type == SceneNodeType::Empty && patch.get_n_children() == 4 {
+            let i = address.quadrant_towards(a, b);
+            address = address.child(i);
+            patch = unsafe { &*patch.children[i] };
+        }
+        if patch.index_count == -1 {
+            return None;
+        }
+        let status = patch.vao_generate.lock().unwrap();
+        let (vertices, parent_heights) = (&status.1.vertices, &status.1.parent_heights);
+        // Skirts come after the grid, as generated in `lod_terrain`
+        let subdivisions = (1 + address.level()) * SUBDIVS_PER_LEVEL;
+        if vertices.len() < 3 * (subdivisions + 1) * (subdivisions + 1) {
+            return None;
+        }
+
+        // Grid cell under the position
+        let (x, z) = address.centre();
+        let scale = address.half_size();
+        let cell = |c: f64, origin: f64| {
+            (((c - origin + scale) / (2.0 * scale) * subdivisions as f64).floor() as i64)
+                .clamp(0, subdivisions as i64 - 1)
+        };
+        let (cx, cz) = (cell(a, x), cell(b, z));
+        let res = subdivisions as i64 + 1;
+        let model_f32 = node.current_transformation_matrix;
+        let vertex = |x: i64, z: i64| -> glm::DVec3 {
+            let i = (z * res + x) as usize;
+            let v = &vertices[3 * i..3 * i + 3];
+            let mut v = glm::vec3(v[0], v[1], v[2]) + patch.model_offset;
+            // Geomorph towards the parent, along the direction from the centre
+            if let Some(parent_height) = parent_heights.get(i) {
+                let world = model_f32 * glm::vec4(v.x, v.y, v.z, 1.0);
+                let morph = view.morph_factor(patch.morph_error, &world.xyz());
+                if morph > 0.0 {
+                    v += glm::normalize(&v) * *parent_height * morph;
+                }
+            }
+            v.cast()
+        };
+        let (t, [v0, v1, v2]) = ray_grid(&dir, vertex, (cx, cz), subdivisions)?;
+        let world = model * glm::vec4(dir.x * t, dir.y * t, dir.z * t, 1.0);
+        let height = glm::length(&(world.xyz() - self.position.cast()));
+        let mut normal = glm::normalize(&glm::cross(&(v1 - v0), &(v2 - v0)));
+        if glm::dot(&normal, &dir) < 0.0 {
+            normal = -normal;
+        }
+        let normal_matrix = glm::transpose(&glm::inverse(&glm::mat4_to_mat3(&model)));
+        let normal = glm::normalize(&(normal_matrix * normal));
+        Some((height, normal.cast()))
+    }
+
+    /// River mask at a position, from 0 outside rivers to 1 in the largest
+    pub fn get_river(&self, pos: &glm::TVec3<f32>) -> f32 {
+        match &self.hydrology_map {
+            Some(hydrology) => hydrology
+                .river
+                .sample(&glm::normalize(&(pos - self.position))),
+            None => 0.0,
+        }
+    }
+
+    /// Lake mask at a position, 1 in lakes
+    pub fn get_lake(&self, pos: &glm::TVec3<f32>) -> f32 {
+        match &self.hydrology_map {
+            Some(hydrology) => hydrology
+                .lake
+                .sample(&glm::normalize(&(pos - self.position))),
+            None => 0.0,
+        }
+    }
+
+    /// Terrain mesh of a patch, relative to its origin. Skirts hide the
+    /// cracks against neighbours of other levels, without them the patch
+    /// only meets neighbours of the same level.
+    pub fn patch_mesh(&self, address: &PatchAddress, skirts: bool) -> mesh::Mesh {
+        let (scale, rotation, position) = (address.scale(), address.rotation(), address.position());
+        let level = address.level();
+        let subdivisions = (1 + level) * SUBDIVS_PER_LEVEL;
+        let mut mesh = mesh::Mesh::cs_plane(scale, rotation, position, subdivisions, None, true);
+        let directions = cubesphere::patch_directions(&scale, &rotation, &position, subdivisions);
+        // The parent's vertices over the patch, a quarter of its grid
+        let parent_directions = (level > 0).then(|| {
+            cubesphere::patch_directions(
+                &scale,
+                &rotation,
+                &position,
+                level * SUBDIVS_PER_LEVEL / 2,
+            )
+        });
+        self.displace_vertices(
+            &mut mesh,
+            &directions,
+            parent_directions.as_deref(),
+            &patch_origin(address),
+            skirts,
         );
-        // self.radius * (
-        //     1.0 + mesh::fractal_noise(
-        //         self.noise_fn, &glm::normalize(&(pos - &position)),
-        //         self.noise_size.into(), self.max_height, 0.0
-        //     )
-        // )
-        self.radius * (1.0 + self.noise(&glm::normalize(&(pos - &position))))
-    }
-
-    fn displace_vertices(&self, mesh: &mut mesh::Mesh) {
-        let mut vertices = util::to_array_of_vec3(mesh.vertices.clone());
-        for i in 0..vertices.len() {
-            // let val = 1.0 + mesh::fractal_noise(
-            //     self.noise_fn,
-            //     &glm::normalize(&vertices[i]),
-            //     self.noise_size.into(),
-            //     self.max_height,
-            //     0.0);
-            let val = 1.0 + self.noise(&glm::normalize(&vertices[i]));
-            vertices[i] *= val;
-        }
-
-        // TODO: Solve the seams, could reuse the noise generator and use polar coordinates
-        let mut normals = util::to_array_of_vec3(mesh.normals.clone());
-        for i in (0..mesh.index_count).step_by(3) {
-            let i = i as usize;
-            let v1 = vertices[mesh.indices[i + 1] as usize] - vertices[mesh.indices[i] as usize];
-            let v2 = vertices[mesh.indices[i + 2] as usize] - vertices[mesh.indices[i] as usize];
-            let norm = glm::normalize(&glm::cross(&v1, &v2));
-            normals[mesh.indices[i] as usize] = norm;
-            normals[mesh.indices[i + 1] as usize] = norm;
-            normals[mesh.indices[i + 2] as usize] = norm;
+        mesh
+    }
+
+    /// The whole terrain at one level of detail, every patch at `level` on
+    /// all faces joined into one mesh, as for exporting. The ocean radius is
+    /// `radius`, and vertices are coloured the way the shader colours them.
+    pub fn uniform_mesh(&self, level: usize) -> mesh::Mesh {
+        let mut planet_mesh = mesh::Mesh::default();
+        for face in 0..6 {
+            let mut addresses = vec![PatchAddress::root(face)];
+            for _ in 0..level {
+                addresses = addresses.iter().flat_map(|a| a.children()).collect();
+            }
+            for address in addresses {
+                // Texture coordinates and tangents are per patch, and parent
+                // heights are only for morphing. Normals on the edges of
+                // faces differ slightly with the tangents they are found
+                // along, they are found again after welding.
+                let mut patch = self.patch_mesh(&address, false);
+                patch.normals.clear();
+                patch.texture_coordinates.clear();
+                patch.tangents.clear();
+                patch.parent_heights.clear();
+                patch.transform(&glm::translation(&patch_origin(&address).cast()));
+                planet_mesh.merge(&patch);
+            }
+        }
+        // Same radius as `Mesh::cs_plane`
+        let colors = (0..planet_mesh.vertex_count())
+            .flat_map(|i| {
+                let v = &planet_mesh.vertices[i * 3..i * 3 + 3];
+                let height = glm::length(&glm::vec3(v[0], v[1], v[2])) * 2.0 - 1.0;
+                let biome = planet_mesh
+                    .biomes
+                    .get(i)
+                    .copied()
+                    .unwrap_or(Biome::None as u32);
+                let color = self.terrain_color(height, biome);
+                [color.x, color.y, color.z, 1.0]
+            })
+            .collect();
+        planet_mesh.colors = colors;
+        planet_mesh.biomes.clear();
+        // Patches repeat the vertices along their edges
+        planet_mesh.weld_vertices(EXPORT_WELD_DISTANCE);
+        let normals = planet_mesh
+            .vertices
+            .chunks_exact(3)
+            .map(|v| self.surface_normal(&glm::normalize(&glm::vec3(v[0], v[1], v[2]).cast())))
+            .collect();
+        planet_mesh.normals = util::from_array_of_vec3(normals);
+        let to_world = self.radius * 2.0;
+        planet_mesh.transform(&glm::scaling(&glm::vec3(to_world, to_world, to_world)));
+        planet_mesh
+    }
+
+    /// Colour of the terrain at a height relative to radius, from the biome
+    /// index of a vertex or else the colour scheme, like in scene.frag
+    pub fn terrain_color(&self, height: f32, biome: u32) -> glm::Vec3 {
+        if biome != Biome::None as u32 && (biome as usize) < N_BIOMES {
+            return self.biomes.colors[biome as usize];
+        }
+        let layer = self
+            .color_thresholds
+            .iter()
+            .position(|&threshold| height < threshold)
+            .unwrap_or(N_LAYERS - 1);
+        self.color_scheme[layer]
+    }
+
+    /// Displace the vertices of a patch, given by their directions from the
+    /// planet centre. Vertices are made relative to the patch origin, so
+    /// their precision doesn't depend on the size of the planet. With the
+    /// directions of the parent's vertices over the patch, each vertex also
+    /// gets the height of the parent's surface, to morph towards.
+    fn displace_vertices(
+        &self,
+        mesh: &mut mesh::Mesh,
+        directions: &[glm::DVec3],
+        parent_directions: Option<&[glm::DVec3]>,
+        origin: &glm::DVec3,
+        skirts: bool,
+    ) {
+        let mut vertices = Vec::with_capacity(directions.len());
+        let mut normals = Vec::with_capacity(directions.len());
+        let mut heights = Vec::with_capacity(directions.len());
+        for dir in directions {
+            let height = self.noise(dir);
+            heights.push(height);
+            if self.biomes.enabled || self.hydrology_map.is_some() {
+                mesh.biomes
+                    .push(self.biome(&dir.cast(), height as f32) as u32);
+            }
+            // Same radius as `Mesh::cs_plane`
+            vertices.push((dir * 0.5 * (1.0 + height) - origin).cast());
+            // Normals from the height field rather than the triangles, so
+            // patches meeting at an edge agree whatever their level or face
+            normals.push(self.surface_normal(dir));
+        }
+        mesh.error = self.mesh_error(&vertices, directions, origin);
+        if let Some(parent_directions) = parent_directions {
+            mesh.parent_heights = self.parent_heights(directions, &heights, parent_directions);
         }
         mesh.normals = util::from_array_of_vec3(normals);
         mesh.vertices = util::from_array_of_vec3(vertices);
+        // Before the skirts, which copy the tangents of their edge
+        mesh.compute_tangents();
+        if skirts {
+            self.add_skirts(mesh, directions, &heights);
+        }
     }
 
-    fn noise(&self, pos: &glm::Vec3) -> f32 {
-        let params = self.noise;
+    /// Bound on how far a patch strays from the terrain, in model units. The
+    /// terrain is sampled in the middle of each cell, on the diagonal shared
+    /// by its triangles, so this is also where the curvature error is largest.
+    fn mesh_error(
+        &self,
+        vertices: &[glm::Vec3],
+        directions: &[glm::DVec3],
+        origin: &glm::DVec3,
+    ) -> f32 {
+        let res = (directions.len() as f64).sqrt() as usize;
+        let mut error = 0.0f64;
+        for z in 0..res - 1 {
+            for x in 0..res - 1 {
+                let (a, b) = (z * res + x, (z + 1) * res + x + 1);
+                let dir = glm::normalize(
+                    &(directions[a] + directions[b] + directions[a + 1] + directions[b - 1]),
+                );
+                let surface = dir * 0.5 * (1.0 + self.noise(&dir)) - origin;
+                let mesh = (vertices[a] + vertices[b]).cast::<f64>() / 2.0;
+                error = error.max(glm::length(&(surface - mesh)));
+            }
+        }
+        error as f32
+    }
+
+    /// Height of the parent patch's surface above each vertex, along the
+    /// direction of the vertex. The parent's vertices over the patch make a
+    /// grid of their own, coarser than the patch's but with the same bounds.
+    fn parent_heights(
+        &self,
+        directions: &[glm::DVec3],
+        heights: &[f64],
+        parent_directions: &[glm::DVec3],
+    ) -> Vec<f32> {
+        let res = (directions.len() as f64).sqrt() as usize;
+        let parent_res = (parent_directions.len() as f64).sqrt() as usize;
+        let parent_vertices = parent_directions
+            .iter()
+            .map(|dir| dir * 0.5 * (1.0 + self.noise(dir)))
+            .collect::<Vec<_>>();
+        let vertex = |x: i64, z: i64| parent_vertices[z as usize * parent_res + x as usize];
+        let cell = |c: usize| (c * (parent_res - 1) / (res - 1)).min(parent_res - 2) as i64;
+        let mut parent_heights = Vec::with_capacity(directions.len());
+        for z in 0..res {
+            for x in 0..res {
+                let i = z * res + x;
+                // Same radius as `Mesh::cs_plane`
+                let radius = 0.5 * (1.0 + heights[i]);
+                let parent = ray_grid(&directions[i], vertex, (cell(x), cell(z)), parent_res - 1)
+                    .map_or(radius, |(t, _)| t);
+                parent_heights.push((parent - radius) as f32);
+            }
+        }
+        parent_heights
+    }
+
+    /// Skirts along the four edges of a patch, so it is watertight against
+    /// neighbours of any level. Where they meet, both edges interpolate
+    /// heights sampled along the same line, so the gap between them is never
+    /// more than the range of heights along the edge.
+    fn add_skirts(&self, mesh: &mut mesh::Mesh, directions: &[glm::DVec3], heights: &[f64]) {
+        let res = (directions.len() as f64).sqrt() as usize;
+        let edges: [Vec<usize>; 4] = [
+            (0..res).collect(),
+            (0..res).map(|x| (res - 1) * res + x).collect(),
+            (0..res).map(|z| z * res).collect(),
+            (0..res).map(|z| z * res + res - 1).collect(),
+        ];
+        for edge in edges {
+            let (min, max) = edge.iter().fold((f64::MAX, f64::MIN), |(min, max), &i| {
+                (min.min(heights[i]), max.max(heights[i]))
+            });
+            // Same radius as `Mesh::cs_plane`, never flat so it still covers
+            // rounding where the heights agree
+            let depth = 0.5 * (max - min).max(SKIRT_MIN_DEPTH * self.max_height as f64);
+            let down = edge
+                .iter()
+                .map(|&i| (-directions[i] * depth).cast())
+                .collect::<Vec<_>>();
+            mesh.add_skirt(&edge, &down);
+        }
+    }
+
+    /// Normal of the terrain surface at a position on the unit sphere, by
+    /// central differences along the surface
+    pub fn surface_normal(&self, dir: &glm::DVec3) -> glm::Vec3 {
+        let (t1, t2) = cubesphere::tangent_basis(&dir.cast());
+        let surface = |t: &glm::Vec3, e: f64| {
+            let d = glm::normalize(&(dir + t.cast() * e));
+            d * (1.0 + self.noise(&d))
+        };
+        let du = surface(&t1, NORMAL_EPSILON) - surface(&t1, -NORMAL_EPSILON);
+        let dv = surface(&t2, NORMAL_EPSILON) - surface(&t2, -NORMAL_EPSILON);
+        // t1 x t2 points outwards, so does du x dv. Where the differences
+        // vanish, the sphere's own normal is the best there is.
+        let normal = glm::cross(&du, &dv);
+        if normal.norm() > 0.0 && normal.iter().all(|v| v.is_finite()) {
+            normal.normalize().cast()
+        } else {
+            dir.cast()
+        }
+    }
+
+    /// Terrain height relative to radius, at a position on the unit sphere.
+    /// Once eroded, the terrain is the eroded height map instead of noise.
+    fn noise(&self, pos: &glm::DVec3) -> f64 {
+        // Baked maps are smooth at their resolution, f32 is plenty
+        let mut height = match &self.erosion_map {
+            Some(erosion_map) => erosion_map.sample(&pos.cast()) as f64,
+            None => self.raw_noise(pos),
+        };
+        if let Some(hydrology) = &self.hydrology_map {
+            height += hydrology.delta.sample(&pos.cast()) as f64;
+        }
+        height
+    }
+
+    /// Terrain height from noise, continents and craters, before erosion
+    fn raw_noise(&self, pos: &glm::DVec3) -> f64 {
+        let mut height = self.detail(pos);
+        if self.continents.enabled {
+            let e = self.continent_mask(pos) - self.continent_level;
+            height = self.continent_height(e, height);
+        }
+        if self.craters.enabled {
+            height += self.craters(pos);
+        }
+        height
+    }
+
+    /// Biome at a position on the unit sphere, with the terrain height there
+    pub fn biome(&self, pos: &glm::Vec3, height: f32) -> Biome {
+        if let Some(hydrology) = &self.hydrology_map {
+            if hydrology.lake.sample(pos) > 0.5 {
+                return Biome::Lake;
+            }
+            if hydrology.river.sample(pos) > 0.15 {
+                return Biome::River;
+            }
+        }
+        if !self.biomes.enabled {
+            return Biome::None;
+        }
+        let params = &self.biomes;
+        let height = height / self.max_height;
+        // Latitude from equator to pole in [0, 1], around the planet's y axis
+        let latitude = pos.y.clamp(-1.0, 1.0).asin().abs() / std::f32::consts::FRAC_PI_2;
+        let temperature = params.temperature
+            - params.polar_cooling * latitude
+            - params.lapse_rate * height.max(0.0);
+        let mut moisture = params.moisture + self.moisture_noise(pos);
+        if let Some(ocean_distance) = &self.ocean_distance {
+            let closeness = (-ocean_distance.sample(pos) / params.coast_distance).exp();
+            moisture = moisture * (1.0 - params.ocean_moisture) + closeness * params.ocean_moisture;
+        }
+        biome::classify(params, temperature, moisture, height, self.has_ocean)
+    }
+
+    /// Modulate detail noise by the signed distance `e` to the coastline in
+    /// continent mask values, positive on land
+    fn continent_height(&self, e: f64, detail: f64) -> f64 {
+        let params = self.continents;
+        let max_height = self.max_height as f64;
+        let coast_detail = params.coast_detail as f64;
+        if e >= 0.0 {
+            // Plains near the coast, full detail and elevation inland
+            let inland = smoothstep(0.0, params.inland_width as f64, e);
+            max_height * params.elevation as f64 * inland
+                + detail * (coast_detail + (1.0 - coast_detail) * inland)
+        } else {
+            // Continental shelf sloping down to the ocean basin
+            let basin = smoothstep(0.0, params.shelf_width as f64, -e);
+            -max_height * params.depth as f64 * basin + detail * coast_detail
+        }
+    }
+
+    /// Sum of impact craters covering a position. Craters are placed in
+    /// cells of a grid, one grid per size class, halving the radius for each
+    /// class. Only neighbouring cells are visited, so the result depends on
+    /// the position alone.
+    fn craters(&self, pos: &glm::DVec3) -> f64 {
+        let params = self.craters;
+        // Profile heights to terrain height, per unit of crater radius
+        let scale = self.max_height as f64 / params.max_radius as f64;
+        let mut height = 0.0;
+        let mut r_max = params.max_radius as f64;
+        let mut class = 0;
+        while r_max >= params.min_radius as f64 && class < MAX_CRATER_CLASSES {
+            let r_min = r_max / 2.0;
+            // Cells fit a crater and its rim within neighbouring cells
+            let reach = 1.0 + 3.0 * params.rim_width as f64;
+            let cell_size = 2.0 * r_max * reach;
+            // Number of cells grows by 4 per class, craters by 2^exponent
+            let probability = params.density as f64
+                * 2f64.powf(class as f64 * (params.size_exponent as f64 - 2.0));
+            let p = pos / cell_size;
+            let cell = glm::vec3(p.x.floor(), p.y.floor(), p.z.floor());
+            for dz in -1..=1 {
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        let c = cell + glm::vec3(dx as f64, dy as f64, dz as f64);
+                        let mut rng = self.cell_rng(&c, class + 1);
+                        if rng() >= probability {
+                            continue;
+                        }
+                        let point = (c + glm::vec3(rng(), rng(), rng())) * cell_size;
+                        // Only keep craters close to the surface, so their
+                        // centres projected onto the sphere stay in the cell
+                        if (glm::length(&point) - 1.0).abs() > cell_size / 4.0 {
+                            continue;
+                        }
+                        // Power law radius within the class by inverse CDF
+                        let a = params.size_exponent as f64;
+                        let u = rng();
+                        let radius =
+                            (r_min.powf(-a) - u * (r_min.powf(-a) - r_max.powf(-a))).powf(-1.0 / a);
+                        let d = glm::length(&(pos - glm::normalize(&point)));
+                        if d < radius * reach {
+                            height += scale * radius * self.crater_profile(d / radius, radius);
+                        }
+                    }
+                }
+            }
+            r_max = r_min;
+            class += 1;
+        }
+        height
+    }
+
+    /// Crater height in the units of `CraterParams`, at distance `x` in radii
+    fn crater_profile(&self, x: f64, radius: f64) -> f64 {
+        let params = self.craters;
+        let rim_height = params.rim_height as f64;
+        let mut h = if x < 1.0 {
+            // Parabolic bowl rising into the rim
+            -params.depth as f64 * (1.0 - x * x) + rim_height * x.powi(4)
+        } else {
+            // Rim falling off outside the crater
+            rim_height * (-((x - 1.0) / params.rim_width as f64).powi(2)).exp()
+        };
+        if params.peak_height > 0.0 && radius >= params.peak_min_radius as f64 {
+            h += params.peak_height as f64 * (-(x / params.peak_width as f64).powi(2)).exp();
+        }
+        h
+    }
+
+    /// Low frequency fractal noise deciding land and ocean basins
+    fn continent_mask(&self, pos: &glm::DVec3) -> f64 {
+        let params = self.continents;
+        let mut noise_sum = 0.0;
+        let mut amp = 1.0;
+        let mut freq = params.frequency as f64;
+        for _ in 0..params.octaves {
+            let point = pos * freq;
+            noise_sum += self.continent_perlin.get([point.x, point.y, point.z]) * amp;
+            freq *= 2.0;
+            amp *= params.roughness as f64;
+        }
+        noise_sum
+    }
+
+    /// Variation in moisture between biomes, roughly in [-0.5, 0.5]
+    fn moisture_noise(&self, pos: &glm::Vec3) -> f32 {
+        let mut noise_sum = 0.0;
+        let mut amp = 0.5;
+        let mut freq = self.biomes.moisture_frequency;
+        for _ in 0..4 {
+            let point = pos * freq;
+            noise_sum +=
+                self.moisture_perlin
+                    .get([point.x as f64, point.y as f64, point.z as f64]) as f32
+                    * amp;
+            freq *= 2.0;
+            amp *= 0.5;
+        }
+        noise_sum
+    }
+
+    /// Terrain detail from the selected noise function
+    fn detail(&self, pos: &glm::DVec3) -> f64 {
+        let max_height = self.max_height as f64;
         match self.noise_fn {
-            _ => {
+            NoiseFunction::Fbm => {
                 // Simple fractal noise. This apparently is also called
                 // fractal Brownian Motion (https://thebookofshaders.com/13/)
-                let mut noise_sum = 0.0;
-                // Initial values
-                let mut amp = params.amplitude;
-                let mut freq = params.frequency;
-                // Properties
-                let gain_pos = pos * params.gain_frequency;
-                let gain = params.gain
-                    + params.gain_amplitude
-                        * ((self.perlin.get([
-                            gain_pos.x.into(),
-                            gain_pos.y.into(),
-                            gain_pos.z.into(),
-                        ]) as f32)
-                            + params.gain_offset);
-                let lac_pos = pos * params.lac_frequency;
-                let lacunarity = params.lacunarity
-                    + params.lac_amplitude
-                        * ((self
-                            .perlin
-                            .get([lac_pos.x.into(), lac_pos.y.into(), lac_pos.z.into()])
-                            as f32)
-                            + params.lac_offset);
-
-                // Iterations - or octaves
-                for _ in 0..params.octaves {
-                    let point = pos * freq;
-                    noise_sum += (self.perlin.get([
-                        (point.x * self.noise.size) as f64, // + seed as f64,
-                        (point.y * self.noise.size) as f64, // + seed as f64,
-                        (point.z * self.noise.size) as f64, // + seed as f64,
-                    ]) as f32)
-                        * amp
-                        * self.max_height;
-                    freq *= lacunarity;
-                    amp *= gain;
+                self.octaves(pos, |p| self.perlin(p)) * max_height
+            }
+            NoiseFunction::Ridged => self.ridged_multifractal(pos) * max_height,
+            NoiseFunction::Billow => {
+                // Folded Perlin, gives rounded hills and dunes
+                self.octaves(pos, |p| 2.0 * self.perlin(p).abs() - 1.0) * max_height
+            }
+            NoiseFunction::Worley => self.octaves(pos, |p| self.worley(p)) * max_height,
+            NoiseFunction::DomainWarp => {
+                // fBm sampled at a position offset by three other fBm sums
+                // (https://iquilezles.org/articles/warp/)
+                let params = self.warp;
+                let q = pos * params.frequency as f64;
+                let offset = glm::vec3(
+                    self.octaves(&q, |p| self.perlin(p)),
+                    self.octaves(&(q + glm::vec3(5.2, 1.3, 2.8)), |p| self.perlin(p)),
+                    self.octaves(&(q + glm::vec3(1.7, 9.2, 4.1)), |p| self.perlin(p)),
+                );
+                let warped = pos + offset * params.strength as f64;
+                self.octaves(&warped, |p| self.perlin(p)) * max_height
+            }
+        }
+    }
+
+    /// Single sample of the Perlin generator, at noise size
+    fn perlin(&self, point: &glm::DVec3) -> f64 {
+        let p = point * self.noise.size as f64;
+        self.perlin.get([p.x, p.y, p.z])
+    }
+
+    /// Gain and lacunarity at a position, each optionally varied by a low
+    /// frequency noise to get some variation over the planet
+    fn gain_lacunarity(&self, pos: &glm::DVec3) -> (f64, f64) {
+        let params = self.noise;
+        let gain_pos = pos * params.gain_frequency as f64;
+        let gain = params.gain as f64
+            + params.gain_amplitude as f64
+                * (self.perlin.get([gain_pos.x, gain_pos.y, gain_pos.z])
+                    + params.gain_offset as f64);
+        let lac_pos = pos * params.lac_frequency as f64;
+        let lacunarity = params.lacunarity as f64
+            + params.lac_amplitude as f64
+                * (self.perlin.get([lac_pos.x, lac_pos.y, lac_pos.z]) + params.lac_offset as f64);
+        (gain, lacunarity)
+    }
+
+    /// Sum octaves of a basis function, scaling frequency by lacunarity and
+    /// amplitude by gain for each iteration
+    fn octaves<F: Fn(&glm::DVec3) -> f64>(&self, pos: &glm::DVec3, basis: F) -> f64 {
+        let params = self.noise;
+        let (gain, lacunarity) = self.gain_lacunarity(pos);
+        let mut noise_sum = 0.0;
+        // Initial values
+        let mut amp = params.amplitude as f64;
+        let mut freq = params.frequency as f64;
+        // Iterations - or octaves
+        for _ in 0..params.octaves {
+            noise_sum += basis(&(pos * freq)) * amp;
+            freq *= lacunarity;
+            amp *= gain;
+        }
+        noise_sum
+    }
+
+    /// Ridged multifractal (Musgrave). Each octave is weighted by the previous
+    /// one, so detail gathers along the sharp ridges and valleys stay smooth.
+    fn ridged_multifractal(&self, pos: &glm::DVec3) -> f64 {
+        let params = self.noise;
+        let ridged = self.ridged;
+        let (gain, lacunarity) = self.gain_lacunarity(pos);
+        let mut noise_sum = 0.0;
+        let mut weight = 1.0;
+        let mut amp = params.amplitude as f64;
+        let mut freq = params.frequency as f64;
+        for _ in 0..params.octaves {
+            let signal = (ridged.offset as f64 - self.perlin(&(pos * freq)).abs())
+                .max(0.0)
+                .powf(ridged.sharpness as f64)
+                * weight;
+            weight = (signal * ridged.weight_gain as f64).clamp(0.0, 1.0);
+            noise_sum += signal * amp;
+            freq *= lacunarity;
+            amp *= gain;
+        }
+        // Ridges are all positive, move base level down to ocean level
+        noise_sum - (params.amplitude * ridged.bias) as f64
+    }
+
+    /// Cellular noise, distance to the closest feature point (F1) or
+    /// difference between the two closest (F2 - F1), mapped to [-1, 1]. Both
+    /// rarely pass a cell's width, they are cut off there.
+    fn worley(&self, point: &glm::DVec3) -> f64 {
+        let params = self.worley;
+        let p = point * self.noise.size as f64;
+        let cell = glm::vec3(p.x.floor(), p.y.floor(), p.z.floor());
+        let (mut f1, mut f2) = (f64::MAX, f64::MAX);
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let c = cell + glm::vec3(dx as f64, dy as f64, dz as f64);
+                    let feature = c + self.cell_point(&c) * params.jitter as f64;
+                    let d = glm::length(&(feature - p));
+                    if d < f1 {
+                        f2 = f1;
+                        f1 = d;
+                    } else if d < f2 {
+                        f2 = d;
+                    }
                 }
-                noise_sum
             }
         }
+        match params.return_type {
+            WorleyReturn::F1 => 1.0 - 2.0 * f1.min(1.0),
+            WorleyReturn::F2MinusF1 => 2.0 * (f2 - f1).min(1.0) - 1.0,
+        }
+    }
+
+    /// Feature point offset in [0, 1) for a cell, hashed from cell and seed
+    fn cell_point(&self, cell: &glm::DVec3) -> glm::DVec3 {
+        let mut rng = self.cell_rng(cell, 0);
+        glm::vec3(rng(), rng(), rng())
+    }
+
+    /// Random numbers in [0, 1) hashed from a cell, a salt and the seed
+    fn cell_rng(&self, cell: &glm::DVec3, salt: u32) -> impl FnMut() -> f64 {
+        let mut h = self.seed
+            ^ salt.wrapping_mul(0x9e3779b9)
+            ^ (cell.x as i32 as u32).wrapping_mul(0x8da6b343)
+            ^ (cell.y as i32 as u32).wrapping_mul(0xd8163841)
+            ^ (cell.z as i32 as u32).wrapping_mul(0xcb1ab31f);
+        move || {
+            // xorshift
+            h ^= h << 13;
+            h ^= h >> 17;
+            h ^= h << 5;
+            (h & 0xffffff) as f64 / 0x1000000 as f64
+        }
     }
 }
 
+/// Basis used for the terrain height, selected by `Planet::noise_fn`
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub enum NoiseFunction {
+    #[default]
+    Fbm, // Fractal Brownian motion on Perlin
+    Ridged,     // Ridged multifractal, sharp mountain ridges
+    Billow,     // Absolute value Perlin, rounded hills and dunes
+    Worley,     // Cellular noise
+    DomainWarp, // fBm with the domain warped by fBm
+}
+
+impl NoiseFunction {
+    pub const ALL: [NoiseFunction; 5] = [
+        NoiseFunction::Fbm,
+        NoiseFunction::Ridged,
+        NoiseFunction::Billow,
+        NoiseFunction::Worley,
+        NoiseFunction::DomainWarp,
+    ];
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct NoiseParams {
     // Initial values
@@ -507,3 +1473,339 @@ impl Default for NoiseParams {
         }
     }
 }
+
+/// Continent mask deciding land and ocean basins. Detail noise is flattened
+/// towards the coastlines, and deepened into basins in the ocean.
+#[derive(Debug, Copy, Clone)]
+pub struct ContinentParams {
+    pub enabled: bool,
+    pub land_fraction: f32, // Target fraction of the surface above ocean level
+    pub frequency: f32,     // Frequency of the mask, lower gives larger continents
+    pub octaves: usize,
+    pub roughness: f32,    // Gain of mask octaves, higher gives rougher coastlines
+    pub inland_width: f32, // Mask distance from coast to full detail and elevation
+    pub shelf_width: f32,  // Mask distance from coast to full ocean depth
+    pub elevation: f32,    // Inland elevation, relative to max_height
+    pub depth: f32,        // Ocean basin depth, relative to max_height
+    pub coast_detail: f32, // Detail amplitude at the coast, relative to inland
+}
+
+impl Default for ContinentParams {
+    fn default() -> Self {
+        ContinentParams {
+            enabled: false,
+            land_fraction: 0.35,
+            frequency: 1.2,
+            octaves: 5,
+            roughness: 0.5,
+            inland_width: 0.25,
+            shelf_width: 0.1,
+            elevation: 0.3,
+            depth: 0.5,
+            coast_detail: 0.2,
+        }
+    }
+}
+
+/// Impact craters added on top of the terrain, each with a bowl, a raised
+/// rim and a central peak for the larger ones. Radii are on the unit sphere.
+/// Heights are in `max_height` for a crater of `max_radius`, smaller craters
+/// are shallower in proportion to their radius.
+#[derive(Debug, Copy, Clone)]
+pub struct CraterParams {
+    pub enabled: bool,
+    pub density: f32,       // Probability of a crater per cell for the largest class
+    pub max_radius: f32,    // Largest crater radius
+    pub min_radius: f32,    // Smallest crater radius, limits number of classes
+    pub size_exponent: f32, // Cumulative size distribution N(>r) ~ r^-size_exponent
+    pub depth: f32,         // Bowl depth
+    pub rim_height: f32,    // Rim height
+    pub rim_width: f32,     // Rim falloff outside the crater, relative to radius
+    pub peak_min_radius: f32, // Craters at least this large get a central peak
+    pub peak_height: f32,   // Central peak height
+    pub peak_width: f32,    // Central peak width, relative to crater radius
+}
+
+impl Default for CraterParams {
+    fn default() -> Self {
+        CraterParams {
+            enabled: false,
+            density: 0.3,
+            max_radius: 0.2,
+            min_radius: 0.01,
+            size_exponent: 2.0,
+            depth: 1.0,
+            rim_height: 0.3,
+            rim_width: 0.3,
+            peak_min_radius: 0.08,
+            peak_height: 0.4,
+            peak_width: 0.2,
+        }
+    }
+}
+
+/// Parameters for `NoiseFunction::Ridged`, octaves and frequencies from `NoiseParams`
+#[derive(Debug, Copy, Clone)]
+pub struct RidgedParams {
+    pub offset: f32,      // Ridge height, subtracted the absolute noise
+    pub sharpness: f32,   // Exponent on each ridge, higher is sharper
+    pub weight_gain: f32, // How much an octave is weighted by the previous
+    pub bias: f32,        // Base level pulled down, relative to amplitude
+}
+
+impl Default for RidgedParams {
+    fn default() -> Self {
+        RidgedParams {
+            offset: 1.0,
+            sharpness: 2.0,
+            weight_gain: 2.0,
+            bias: 0.5,
+        }
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub enum WorleyReturn {
+    #[default]
+    F1, // Distance to closest feature point, round cells
+    F2MinusF1, // Distance between two closest, sharp cell walls
+}
+
+impl WorleyReturn {
+    pub const ALL: [WorleyReturn; 2] = [WorleyReturn::F1, WorleyReturn::F2MinusF1];
+}
+
+/// Parameters for `NoiseFunction::Worley`, octaves and frequencies from `NoiseParams`
+#[derive(Debug, Copy, Clone)]
+pub struct WorleyParams {
+    pub return_type: WorleyReturn,
+    pub jitter: f32, // Displacement of feature points within their cells
+}
+
+impl Default for WorleyParams {
+    fn default() -> Self {
+        WorleyParams {
+            return_type: WorleyReturn::F1,
+            jitter: 1.0,
+        }
+    }
+}
+
+/// Parameters for `NoiseFunction::DomainWarp`, octaves and frequencies from `NoiseParams`
+#[derive(Debug, Copy, Clone)]
+pub struct WarpParams {
+    pub strength: f32,  // Distance the domain is offset
+    pub frequency: f32, // Frequency of the warping fBm relative to the terrain
+}
+
+impl Default for WarpParams {
+    fn default() -> Self {
+        WarpParams {
+            strength: 0.4,
+            frequency: 0.5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// A planet with every noise function, Worley noise with both returns
+    fn noise_planets(seed: u32) -> Vec<Planet> {
+        let mut planets = vec![];
+        for noise_fn in NoiseFunction::ALL {
+            for return_type in WorleyReturn::ALL {
+                if noise_fn != NoiseFunction::Worley && return_type != WorleyReturn::F1 {
+                    continue;
+                }
+                let mut planet = Planet::with_seed(seed);
+                planet.max_height = 1.0;
+                planet.noise_fn = noise_fn;
+                planet.worley.return_type = return_type;
+                planets.push(planet);
+            }
+        }
+        planets
+    }
+
+    fn samples() -> Vec<glm::DVec3> {
+        util::fibonacci_sphere(1000)
+            .iter()
+            .map(|p| p.cast())
+            .collect()
+    }
+
+    #[test]
+    fn noise_is_deterministic_per_seed() {
+        let points = samples();
+        let others = noise_planets(8);
+        for ((a, b), other) in noise_planets(7).iter().zip(noise_planets(7)).zip(others) {
+            let heights =
+                |planet: &Planet| points.iter().map(|p| planet.detail(p)).collect::<Vec<_>>();
+            assert_eq!(heights(a), heights(&b), "{:?}", a.noise_fn);
+            assert_ne!(heights(a), heights(&other), "{:?}", a.noise_fn);
+        }
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        // Octave amplitudes add up to this, each basis is within [-1, 1]
+        let params = NoiseParams::default();
+        let bound: f64 = (0..params.octaves)
+            .map(|i| (params.amplitude * params.gain.powi(i as i32)) as f64)
+            .sum();
+        let points = samples();
+        for planet in noise_planets(7) {
+            let (mut lo, mut hi) = (f64::MAX, f64::MIN);
+            for p in &points {
+                let basis = match planet.noise_fn {
+                    NoiseFunction::Worley => planet.worley(p),
+                    _ => planet.perlin(p),
+                };
+                assert!(
+                    (-1.0..=1.0).contains(&basis),
+                    "{:?} basis {}",
+                    planet.noise_fn,
+                    basis
+                );
+                let height = planet.detail(p);
+                (lo, hi) = (lo.min(height), hi.max(height));
+            }
+            let (min, max) = match planet.noise_fn {
+                // Ridges are in [0, 1], moved down by the bias
+                NoiseFunction::Ridged => {
+                    let bias = (params.amplitude * planet.ridged.bias) as f64;
+                    (-bias, bound - bias)
+                }
+                _ => (-bound, bound),
+            };
+            let name = format!("{:?} {:?}", planet.noise_fn, planet.worley.return_type);
+            assert!(min <= lo && hi <= max, "{} in [{}, {}]", name, lo, hi);
+            assert!(
+                hi - lo > 0.25 * (max - min),
+                "{} is flat, [{}, {}]",
+                name,
+                lo,
+                hi
+            );
+        }
+    }
+
+    #[test]
+    fn continents_cover_the_land_fraction() {
+        for land in [0.3, 0.45, 0.7] {
+            let mut planet = Planet::with_seed(3);
+            planet.max_height = 0.05;
+            planet.continents.enabled = true;
+            planet.continents.land_fraction = land;
+            planet.bake_terrain();
+            let fraction = |points: &[glm::DVec3]| {
+                let above = points.iter().filter(|p| planet.raw_noise(p) > 0.0).count();
+                above as f32 / points.len() as f32
+            };
+            // On the samples the coastline was placed by, and elsewhere
+            let placed: Vec<glm::DVec3> = util::fibonacci_sphere(CONTINENT_SAMPLES)
+                .iter()
+                .map(|p| p.cast())
+                .collect();
+            assert!(
+                (fraction(&placed) - land).abs() < 0.005,
+                "{} of {}",
+                fraction(&placed),
+                land
+            );
+            let other = samples();
+            assert!(
+                (fraction(&other) - land).abs() < 0.03,
+                "{} of {}",
+                fraction(&other),
+                land
+            );
+        }
+    }
+
+    #[test]
+    fn craters_depend_on_position_only() {
+        let mut planet = Planet::with_seed(11);
+        planet.max_height = 0.05;
+        planet.noise.amplitude = 0.0;
+        planet.craters.enabled = true;
+        planet.craters.density = 1.0;
+        planet.craters.min_radius = 0.04;
+        // A patch, the patches around it, some on other faces, and finer ones
+        let patch = PatchAddress::root(0).child(0);
+        let mut addresses = vec![patch.clone()];
+        addresses.extend(patch.neighbours());
+        addresses.extend(patch.children());
+
+        // Radius of every vertex, by its direction rounded to a grid
+        let mut radii: HashMap<[i64; 3], f64> = HashMap::new();
+        let (mut shared, mut cratered) = (0, 0);
+        for address in &addresses {
+            let mesh = planet.patch_mesh(address, false);
+            for v in util::to_array_of_vec3(mesh.vertices) {
+                let p = v.cast() + patch_origin(address);
+                let (dir, radius) = (glm::normalize(&p), glm::length(&p));
+                let height = planet.craters(&dir);
+                assert!((radius - 0.5 * (1.0 + height)).abs() < 1e-6);
+                cratered += (height.abs() > 1e-4) as usize;
+                let key = (dir * 1e4).map(|c| c.round() as i64).into();
+                if let Some(other) = radii.insert(key, radius) {
+                    assert!((other - radius).abs() < 1e-6, "{:?} in {:?}", dir, address);
+                    shared += 1;
+                }
+            }
+        }
+        assert!(
+            shared > 100 && cratered > 100,
+            "{} shared, {} cratered",
+            shared,
+            cratered
+        );
+    }
+
+    #[test]
+    fn normals_match_along_shared_edges() {
+        let mut planet = Planet::with_seed(3);
+        planet.max_height = 0.05;
+        // Quadrant 0 is in a corner of the face, two of its neighbours are on
+        // other faces. Every neighbour also meets the patch's children.
+        let patch = PatchAddress::root(0).child(0);
+        let mut addresses = vec![patch.clone()];
+        addresses.extend(patch.neighbours());
+        addresses.extend(patch.children());
+
+        // Normal of every vertex, by its direction rounded to a grid
+        let mut normals: HashMap<[i64; 3], (glm::Vec3, &PatchAddress)> = HashMap::new();
+        let (mut across_faces, mut across_levels) = (0, 0);
+        for address in &addresses {
+            let mesh = planet.patch_mesh(address, false);
+            let vertices = util::to_array_of_vec3(mesh.vertices);
+            for (v, n) in vertices.iter().zip(util::to_array_of_vec3(mesh.normals)) {
+                let dir = glm::normalize(&(v.cast() + patch_origin(address)));
+                let key = (dir * 1e4).map(|c| c.round() as i64).into();
+                let Some(&(other, other_address)) = normals.get(&key) else {
+                    normals.insert(key, (n, address));
+                    continue;
+                };
+                assert!(
+                    glm::distance(&n, &other) < 1e-4,
+                    "{:?} in {:?} and {:?}",
+                    dir,
+                    address,
+                    other_address
+                );
+                across_faces += (address.face != other_address.face) as usize;
+                across_levels += (address.level() != other_address.level()) as usize;
+            }
+        }
+        assert!(
+            across_faces > 10 && across_levels > 10,
+            "{} across faces, {} across levels",
+            across_faces,
+            across_levels
+        );
+    }
+}
@@ -1,7 +1,11 @@
+use crate::disk_cache;
 use crate::procedural_planet as planet;
 use crate::scene_graph::{Node, SceneNode, SceneNodeType};
+use crate::util;
 
-pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
+/// Planets, their scene nodes and the planets that are light sources. Moons
+/// take their terrain noise from `conf`, and terrain is cached where it says.
+pub fn create_scene(conf: &util::Config) -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     let mut planets = vec![];
     let mut planet_nodes = vec![];
     let mut lightsources = vec![];
@@ -38,6 +42,9 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     let planet_earth0 = planet.planet_id;
     planet.max_height = 0.03;
     planet.noise.size = 25.0;
+    planet.continents.enabled = true;
+    planet.continents.land_fraction = 0.4;
+    planet.biomes.enabled = true;
     planet.ocean_dark_color = glm::vec3(0.001, 0.03, 0.01);
     planet.ocean_light_color = glm::vec3(0.04, 0.37, 0.33);
     planet.emission = glm::vec3(0.03, 0.32, 0.37);
@@ -70,6 +77,12 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     planet.max_height = 0.08;
     planet.noise.size = 4.0;
     planet.max_lod += 1;
+    planet.continents.enabled = true;
+    planet.continents.land_fraction = 0.3;
+    planet.continents.roughness = 0.6;
+    planet.erosion.enabled = true;
+    planet.biomes.enabled = true;
+    planet.hydrology.enabled = true;
     planet.noise.octaves += 1;
     planet.noise.amplitude = 0.8;
     planet.noise.gain_amplitude = 0.7;
@@ -109,6 +122,8 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     planet.max_height = 0.03;
     planet.noise.size = 10.0;
     planet.has_ocean = false;
+    planet.thermal.enabled = true;
+    planet.thermal.talus_angle = 0.2;
     planet.emission = glm::vec3(0.6118, 0.1255, 0.1255);
     planet.color_scheme = [
         glm::vec3(0.6118, 0.1255, 0.1255),
@@ -144,7 +159,10 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     planet.parent_id = planet_mars;
     planet.max_height = 0.003;
     planet.noise.size = 6.0;
+    planet.noise_fn = conf.moon_noise[0];
+    planet.worley.return_type = conf.worley_return;
     planet.has_ocean = false;
+    planet.craters.enabled = true;
     planet.emission = glm::vec3(0.118, 0.1255, 0.1255);
     planet.color_scheme = [
         glm::vec3(0.118, 0.1255, 0.1255),
@@ -176,7 +194,10 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     planet.parent_id = planet_earth0;
     planet.max_height = 0.09;
     planet.noise.size = 5.4;
+    planet.noise_fn = conf.moon_noise[1];
+    planet.worley.return_type = conf.worley_return;
     planet.has_ocean = false;
+    planet.craters.enabled = true;
     planet.emission = glm::vec3(0.118, 0.1255, 0.1255);
     planet.color_scheme = [
         glm::vec3(0.118, 0.1255, 0.1255),
@@ -208,6 +229,8 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     planet.parent_id = planet_earth1;
     planet.max_height = 0.12;
     planet.noise.size = 3.4;
+    planet.noise_fn = conf.moon_noise[2];
+    planet.worley.return_type = conf.worley_return;
     planet.has_ocean = false;
     planet.emission = glm::vec3(0.118, 0.1255, 0.1255);
     planet.color_scheme = [
@@ -240,6 +263,8 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     planet.parent_id = planet_earth1;
     planet.max_height = 0.09;
     planet.noise.size = 3.6;
+    planet.noise_fn = conf.moon_noise[3];
+    planet.worley.return_type = conf.worley_return;
     planet.has_ocean = false;
     planet.emission = glm::vec3(0.118, 0.1255, 0.1255);
     planet.color_scheme = [
@@ -272,6 +297,8 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     planet.parent_id = planet_earth1;
     planet.max_height = 0.04;
     planet.noise.size = 2.7;
+    planet.noise_fn = conf.moon_noise[4];
+    planet.worley.return_type = conf.worley_return;
     planet.has_ocean = false;
     planet.emission = glm::vec3(0.118, 0.1255, 0.1255);
     planet.color_scheme = [
@@ -376,6 +403,9 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     planet.noise.lac_offset = 0.5;
     planet.noise.lac_amplitude = 0.5;
     planet.noise.lac_frequency = 0.4;
+    planet.continents.enabled = true;
+    planet.continents.land_fraction = 0.45;
+    planet.continents.frequency = 0.8;
     planet.emission = glm::vec3(0.4588, 0.6588, 0.4588);
     planet.ocean_dark_color = glm::vec3(0.06, 0.06, 0.11);
     planet.ocean_light_color = glm::vec3(0.15, 0.14, 0.40);
@@ -404,5 +434,19 @@ pub fn create_scene() -> (Vec<planet::Planet>, Vec<Node>, Vec<usize>) {
     planets.push(planet);
     planet_nodes.push(planet_node);
 
+    std::thread::scope(|s| {
+        for planet in planets.iter_mut() {
+            planet.cache_dir = conf.patch_cache_dir.clone();
+            s.spawn(|| planet.bake_terrain());
+        }
+    });
+    // Patches of earlier seeds and parameters are never loaded again
+    if let Some(directory) = &conf.patch_cache_dir {
+        let keep = planets.iter().map(|p| p.cache_id()).collect::<Vec<_>>();
+        if let Err(e) = disk_cache::prune(directory, &keep) {
+            eprintln!("Could not prune {}: {}", directory.display(), e);
+        }
+    }
+
     (planets, planet_nodes, lightsources)
 }
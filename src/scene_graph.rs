@@ -5,7 +5,7 @@ use std::pin::Pin;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
-use crate::{mesh, util};
+use crate::{mesh, worker_pool};
 
 static NODE_COUNTER: AtomicU64 = AtomicU64::new(0);
 
@@ -33,6 +33,7 @@ pub enum VAOStatus {
     NotStarted,
     Generating,
     Ready,
+    Failed, // Generation panicked, not tried again
 }
 impl Default for VAOStatus {
     fn default() -> Self {
@@ -73,6 +74,12 @@ pub struct SceneNode {
     pub rotation: glm::Vec3,        // How I should be rotated
     pub scale: glm::Vec3,           // How I should be scaled
     pub reference_point: glm::Vec3, // About which point I shall rotate about
+    pub model_offset: glm::Vec3,    // Where my vertices are relative to the model
+    pub bounding_radius: f32,       // Around model_offset, holds all my vertices
+    pub inner_radius: f32,          // From the model centre, my vertices are further
+    pub geometric_error: f32,       // How far my mesh strays from the terrain
+    pub morph_error: f32, // My parent's geometric error in world units, I morph towards it
+    pub last_used: u64,   // Frame the terrain quadtree last reached me
 
     pub node_type: SceneNodeType,
     pub name: String,
@@ -82,6 +89,7 @@ pub struct SceneNode {
     pub vao: mesh::VAOobj,                                 // What I should draw
     pub index_count: i32,                                  // How much of it I shall draw
     pub vao_generate: Arc<Mutex<(VAOStatus, mesh::Mesh)>>, // False if not ready
+    pub job: Option<worker_pool::JobHandle>,               // Generating my mesh
 
     // IDs of maps
     pub texture_id: Option<u32>,
@@ -98,6 +106,12 @@ impl SceneNode {
             rotation: glm::zero(),
             scale: glm::vec3(1.0, 1.0, 1.0),
             reference_point: glm::zero(),
+            model_offset: glm::zero(),
+            bounding_radius: 0.0,
+            inner_radius: 0.0,
+            geometric_error: 0.0,
+            morph_error: 0.0,
+            last_used: 0,
             node_type: SceneNodeType::Empty,
             name: String::new(),
             current_transformation_matrix: glm::identity(),
@@ -105,6 +119,7 @@ impl SceneNode {
             vao: Default::default(),
             index_count: -1,
             vao_generate: Arc::new(Mutex::new((VAOStatus::default(), mesh::Mesh::default()))),
+            job: None,
             texture_id: None,
             children: vec![],
         })))
@@ -118,6 +133,12 @@ impl SceneNode {
             rotation: glm::zero(),
             scale: glm::vec3(1.0, 1.0, 1.0),
             reference_point: glm::zero(),
+            model_offset: glm::zero(),
+            bounding_radius: 0.0,
+            inner_radius: 0.0,
+            geometric_error: 0.0,
+            morph_error: 0.0,
+            last_used: 0,
             node_type,
             name: String::new(),
             current_transformation_matrix: glm::identity(),
@@ -125,6 +146,7 @@ impl SceneNode {
             vao: Default::default(),
             index_count: -1,
             vao_generate: Arc::new(Mutex::new((VAOStatus::default(), mesh::Mesh::default()))),
+            job: None,
             texture_id: None,
             children: vec![],
         })))
@@ -138,6 +160,12 @@ impl SceneNode {
             rotation: glm::zero(),
             scale: glm::vec3(1.0, 1.0, 1.0),
             reference_point: glm::zero(),
+            model_offset: glm::zero(),
+            bounding_radius: 0.0,
+            inner_radius: 0.0,
+            geometric_error: 0.0,
+            morph_error: 0.0,
+            last_used: 0,
             node_type: SceneNodeType::Geometry,
             name: String::new(),
             current_transformation_matrix: glm::identity(),
@@ -145,6 +173,7 @@ impl SceneNode {
             vao: vao,
             index_count: vao.n,
             vao_generate: Arc::new(Mutex::new((VAOStatus::Ready, mesh::Mesh::default()))),
+            job: None,
             texture_id: None,
             children: vec![],
         })))
@@ -299,6 +328,11 @@ impl SceneNode {
                         self.current_transformation_matrix.as_ptr(),
                     );
 
+                    let u_model_offset = sh.get_uniform_location("u_model_offset");
+                    gl::Uniform3fv(u_model_offset, 1, self.model_offset.as_ptr());
+                    let u_morph_error = sh.get_uniform_location("u_morph_error");
+                    gl::Uniform1f(u_morph_error, self.morph_error);
+
                     // Bind textures, or signal that none exist
                     let u_has_texture = sh.get_uniform_location("u_has_texture");
                     if let Some(texture_id) = self.texture_id {
@@ -329,67 +363,9 @@ impl SceneNode {
     }
 
     pub fn update_buffers(&mut self, mesh: &mesh::Mesh) {
-        unsafe { self.update_vertex_buffer(mesh) };
-        unsafe { self.update_normal_buffer(mesh) };
-        unsafe { self.update_texture_buffer(mesh) };
-        unsafe { self.update_index_buffer(mesh) };
-    }
-    pub unsafe fn update_vertex_buffer(&self, mesh: &mesh::Mesh) {
-        gl::BindVertexArray(self.vao.vao);
-        gl::BindBuffer(gl::ARRAY_BUFFER, self.vao.vbo);
-
-        let vbuf_size = util::byte_size_of_array(&mesh.vertices);
-        let vbuf_data = util::pointer_to_array(&mesh.vertices);
-
-        gl::BufferData(
-            gl::ARRAY_BUFFER,
-            vbuf_size,
-            vbuf_data as *const _,
-            gl::STATIC_DRAW,
-        );
-    }
-    pub unsafe fn update_index_buffer(&mut self, mesh: &mesh::Mesh) {
-        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.vao.ibo);
-
-        let ibuf_size = util::byte_size_of_array(&mesh.indices);
-        let ibuf_data = util::pointer_to_array(&mesh.indices);
-
-        gl::BufferData(
-            gl::ELEMENT_ARRAY_BUFFER,
-            ibuf_size,
-            ibuf_data as *const _,
-            gl::STATIC_DRAW,
-        );
+        unsafe { self.vao.update(mesh) };
         self.index_count = mesh.index_count;
     }
-    pub unsafe fn update_normal_buffer(&self, mesh: &mesh::Mesh) {
-        gl::BindVertexArray(self.vao.vao);
-        gl::BindBuffer(gl::ARRAY_BUFFER, self.vao.nbo);
-
-        let nbuf_size = util::byte_size_of_array(&mesh.normals);
-        let nbuf_data = util::pointer_to_array(&mesh.normals);
-
-        gl::BufferData(
-            gl::ARRAY_BUFFER,
-            nbuf_size,
-            nbuf_data as *const _,
-            gl::STATIC_DRAW,
-        );
-    }
-    pub unsafe fn update_texture_buffer(&self, mesh: &mesh::Mesh) {
-        gl::BindVertexArray(self.vao.vao);
-        gl::BindBuffer(gl::ARRAY_BUFFER, self.vao.texbo);
-
-        let tbuf_size = util::byte_size_of_array(&mesh.texture_coordinates);
-        let tbuf_data = util::pointer_to_array(&mesh.texture_coordinates);
-
-        gl::BufferData(
-            gl::ARRAY_BUFFER,
-            tbuf_size,
-            tbuf_data as *const _,
-            gl::STATIC_DRAW,
-        );
-    }
 }
 
 // You can also use square brackets to access the children of a SceneNode
@@ -124,6 +124,19 @@ pub fn vec2_f64_to_f632(v: &glm::TVec2<f64>) -> glm::TVec2<f32> {
     glm::vec2(v.x as _, v.y as _)
 }
 
+/// Evenly distributed points on the unit sphere, along a Fibonacci spiral
+pub fn fibonacci_sphere(n: usize) -> Vec<glm::Vec3> {
+    let golden_angle = std::f32::consts::PI * (3.0 - 5.0f32.sqrt());
+    (0..n)
+        .map(|i| {
+            let y = 1.0 - 2.0 * (i as f32 + 0.5) / n as f32;
+            let r = (1.0 - y * y).sqrt();
+            let theta = golden_angle * i as f32;
+            glm::vec3(r * theta.cos(), y, r * theta.sin())
+        })
+        .collect()
+}
+
 //-----------------------------------------------------------------------------/
 // OpenGL debug utils
 //-----------------------------------------------------------------------------/
@@ -191,7 +204,14 @@ pub struct Config {
     pub render_limit: f32,
     pub player_height: f32,
     pub jump_speed: f32,
-    //init_direction: [f32; 3],
+    pub lod_pixel_error: f32,
+    pub patch_gpu_budget: f32,
+    pub patch_cpu_budget: f32,
+    pub cube_projection: crate::cubesphere::Projection,
+    pub moon_noise: [crate::procedural_planet::NoiseFunction; 5],
+    pub worley_return: crate::procedural_planet::WorleyReturn,
+    pub patch_cache_dir: Option<std::path::PathBuf>, // Terrain isn't cached without
+                                                     //init_direction: [f32; 3],
 }
 
 impl Config {
@@ -211,6 +231,29 @@ impl Config {
 
         arr.try_into().unwrap()
     }
+    /// One of `choices`, by its index
+    fn parse_choice<T: Copy + std::fmt::Debug>(key: &str, val: &str, choices: &[T]) -> T {
+        val.trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| choices.get(i).copied())
+            .unwrap_or_else(|| {
+                panic!(
+                    "{} is {}, expected an index of {:?}",
+                    key,
+                    val.trim(),
+                    choices
+                )
+            })
+    }
+    /// A path relative to the directory of the executable, so it is the same
+    /// wherever the game is run from. Absolute paths are kept.
+    fn from_executable(path: &str) -> std::path::PathBuf {
+        std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.join(path)))
+            .unwrap_or_else(|| path.into())
+    }
     pub fn load() -> Self {
         use std::fs;
         let mut conf = Config {
@@ -246,6 +289,36 @@ impl Config {
                     "render_limit" => conf.render_limit = val.trim().parse::<f32>().unwrap(),
                     "player_height" => conf.player_height = val.trim().parse::<f32>().unwrap(),
                     "jump_speed" => conf.jump_speed = val.trim().parse::<f32>().unwrap(),
+                    "lod_pixel_error" => conf.lod_pixel_error = val.trim().parse::<f32>().unwrap(),
+                    "patch_gpu_budget" => {
+                        conf.patch_gpu_budget = val.trim().parse::<f32>().unwrap()
+                    }
+                    "patch_cpu_budget" => {
+                        conf.patch_cpu_budget = val.trim().parse::<f32>().unwrap()
+                    }
+                    "cube_projection" => {
+                        conf.cube_projection =
+                            Self::parse_choice(key, val, &crate::cubesphere::Projection::ALL)
+                    }
+                    "moon_noise" => {
+                        conf.moon_noise = Self::parse_array::<String, 5>(val).map(|i| {
+                            Self::parse_choice(
+                                key,
+                                &i,
+                                &crate::procedural_planet::NoiseFunction::ALL,
+                            )
+                        })
+                    }
+                    "worley_return" => {
+                        conf.worley_return = Self::parse_choice(
+                            key,
+                            val,
+                            &crate::procedural_planet::WorleyReturn::ALL,
+                        )
+                    }
+                    "patch_cache_dir" => {
+                        conf.patch_cache_dir = Some(Self::from_executable(val.trim()))
+                    }
                     //"init_direction" => conf.init_direction = Self::parse_array::<f32, 3>(val),
                     &_ => (),
                 }
@@ -254,6 +327,19 @@ impl Config {
     }
 }
 
+/// Left, right, bottom and top planes of the view frustum, as (normal,
+/// distance) with normals pointing inwards, from a view projection matrix
+pub fn frustum_planes(view_projection: &glm::Mat4) -> [glm::Vec4; 4] {
+    let row = |i: usize| view_projection.row(i).transpose();
+    [
+        row(3) + row(0),
+        row(3) - row(0),
+        row(3) + row(1),
+        row(3) - row(1),
+    ]
+    .map(|plane| plane / glm::length(&plane.xyz()))
+}
+
 // Connected vectors
 
 // Calculate right camera vector from horixontal angle
@@ -0,0 +1,209 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicU64};
+use std::sync::{Arc, Condvar, Mutex};
+
+//-----------------------------------------------------------------------------/
+// Worker pool
+//-----------------------------------------------------------------------------/
+// A fixed number of threads take jobs from a shared queue, closest first.
+// Jobs can be cancelled while they wait, or told to throw away their result
+// while they run, so the queue only holds work that is still wanted. Jobs
+// that are still wanted can move closer while they wait, the queue is
+// reordered when they do. A job that panics fails alone, its worker goes on.
+
+/// Order a job is taken from the queue in, lowest first
+#[derive(Debug, Copy, Clone)]
+pub struct Priority {
+    pub distance: f32, // To the player, `JobHandle::set_distance` changes it
+    pub level: usize,  // Coarser levels first at the same distance
+}
+
+/// Shared with a job, to cancel it or change its distance
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    cancelled: Arc<AtomicBool>,
+    failed: Arc<AtomicBool>,    // The task panicked
+    distance: Arc<AtomicU32>,   // Bits of `Priority::distance`
+    reordered: Arc<AtomicBool>, // The queue's, set when a distance changes
+}
+
+impl JobHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(atomic::Ordering::Relaxed)
+    }
+
+    fn distance(&self) -> f32 {
+        f32::from_bits(self.distance.load(atomic::Ordering::Relaxed))
+    }
+
+    /// Move a waiting job to a new distance from the player
+    pub fn set_distance(&self, distance: f32) {
+        if distance != self.distance() {
+            self.distance
+                .store(distance.to_bits(), atomic::Ordering::Relaxed);
+            self.reordered.store(true, atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+type Task = Box<dyn FnOnce(&JobHandle) + Send>;
+
+struct Job {
+    level: usize,
+    order: u64, // Submission order, first come first served on ties
+    handle: JobHandle,
+    task: Task,
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Job {}
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Job {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed for a min-heap
+        other
+            .handle
+            .distance()
+            .total_cmp(&self.handle.distance())
+            .then(other.level.cmp(&self.level))
+            .then(other.order.cmp(&self.order))
+    }
+}
+
+struct Queue {
+    jobs: Mutex<BinaryHeap<Job>>,
+    available: Condvar,
+    reordered: Arc<AtomicBool>, // Some distance changed since the heap was built
+}
+
+pub struct WorkerPool {
+    queue: Arc<Queue>,
+    submitted: AtomicU64,
+}
+
+impl WorkerPool {
+    /// Start `workers` threads, they live as long as the program
+    pub fn new(workers: usize) -> Self {
+        let queue = Arc::new(Queue {
+            jobs: Mutex::new(BinaryHeap::new()),
+            available: Condvar::new(),
+            reordered: Arc::default(),
+        });
+        for _ in 0..workers {
+            let queue = queue.clone();
+            std::thread::spawn(move || loop {
+                let job = {
+                    let mut jobs = queue.jobs.lock().unwrap();
+                    loop {
+                        if queue.reordered.swap(false, atomic::Ordering::Relaxed) {
+                            *jobs = BinaryHeap::from(std::mem::take(&mut *jobs).into_vec());
+                        }
+                        match jobs.pop() {
+                            Some(job) => break job,
+                            None => jobs = queue.available.wait(jobs).unwrap(),
+                        }
+                    }
+                };
+                if !job.handle.is_cancelled() {
+                    let task = job.task;
+                    let handle = &job.handle;
+                    if panic::catch_unwind(AssertUnwindSafe(|| task(handle))).is_err() {
+                        handle.failed.store(true, atomic::Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+        WorkerPool {
+            queue,
+            submitted: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a job. The task gets the handle, to see if it was cancelled
+    /// while it ran.
+    pub fn submit<F>(&self, priority: Priority, task: F) -> JobHandle
+    where
+        F: FnOnce(&JobHandle) + Send + 'static,
+    {
+        let handle = JobHandle {
+            cancelled: Arc::default(),
+            failed: Arc::default(),
+            distance: Arc::new(AtomicU32::new(priority.distance.to_bits())),
+            reordered: self.queue.reordered.clone(),
+        };
+        let job = Job {
+            level: priority.level,
+            order: self.submitted.fetch_add(1, atomic::Ordering::Relaxed),
+            handle: handle.clone(),
+            task: Box::new(task),
+        };
+        let mut jobs = self.queue.jobs.lock().unwrap();
+        // Drop what was cancelled, so the queue doesn't fill up with it
+        if jobs.iter().any(|job| job.handle.is_cancelled()) {
+            jobs.retain(|job| !job.handle.is_cancelled());
+        }
+        jobs.push(job);
+        self.queue.available.notify_one();
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn worker_survives_a_panicking_job() {
+        let pool = WorkerPool::new(1);
+        let priority = Priority {
+            distance: 1.0,
+            level: 0,
+        };
+        let failing = pool.submit(priority, |_| panic!("job failed on purpose"));
+        let (sender, receiver) = mpsc::channel();
+        pool.submit(priority, move |_| sender.send(()).unwrap());
+        receiver
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap();
+        assert!(failing.has_failed());
+    }
+
+    #[test]
+    fn moved_jobs_are_taken_in_their_new_order() {
+        let pool = WorkerPool::new(1);
+        let priority = |distance| Priority { distance, level: 0 };
+        // Keep the worker busy until all jobs are queued
+        let (start, started) = mpsc::channel::<()>();
+        pool.submit(priority(0.0), move |_| started.recv().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        let handles: Vec<JobHandle> = (0..3)
+            .map(|i| {
+                let sender = sender.clone();
+                pool.submit(priority(i as f32), move |_| sender.send(i).unwrap())
+            })
+            .collect();
+        handles[2].set_distance(-1.0);
+        start.send(()).unwrap();
+        let order: Vec<i32> = (0..3).map(|_| receiver.recv().unwrap()).collect();
+        assert_eq!(order, vec![2, 0, 1]);
+    }
+}